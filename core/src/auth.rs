@@ -1,339 +1,1713 @@
-use rand::{distributions::Alphanumeric, Rng};
-use redis::Commands;
-use sqlx::{Pool, Postgres};
+use std::{cmp::Reverse, str::FromStr, sync::Arc};
 
 use crate::{
-    util::{self, connect, generate_token, hash},
+    jwt::{self, Claims, Jwks, JwtConfig, SigningKey, DEFAULT_JWT_EXPIRE_TIME},
+    mail::Mailer,
+    password::{self, HashAlgorithm},
+    store::{
+        EmailTokenKind, LoginFailures, MemoryTokenStore, MemoryUserStore, Passkey,
+        PostgresUserStore, RedisTokenStore, Session, Token, TokenStore, Totp, User, UserStore,
+        WebauthnCeremony, WebauthnChallenge,
+    },
+    totp,
+    util::{generate_token, hash_token, is_token, random_recovery_code, random_token, unix_now},
+    webauthn::{self, LoginCredential, RegistrationCredential, WebauthnConfig},
     AuthError,
 };
 
+/// How long the tokens sent by email to verify an address stay valid
+const EMAIL_TOKEN_EXPIRE_TIME: u64 = 24 * 60 * 60;
+
+/// How long password reset tokens stay valid
+const PASSWORD_RESET_EXPIRE_TIME: u64 = 60 * 60;
+
+/// How long a login waits for a second factor
+const MFA_CHALLENGE_EXPIRE_TIME: usize = 5 * 60;
+
+/// Codes that can be tried for one login before it has to start over
+const MAX_MFA_ATTEMPTS: u32 = 5;
+
+/// How many recovery codes a user gets at a time
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// How long an authenticator has to answer a passkey challenge
+const WEBAUTHN_CHALLENGE_EXPIRE_TIME: usize = 5 * 60;
+
+/// How many failed logins are allowed before logins are refused for a while,
+/// see [`Auth::login_limits`]
+#[derive(Clone, Debug)]
+pub struct LoginLimits {
+    /// Failed logins for one email before it is locked, 0 for no limit
+    pub max_account_failures: u32,
+    /// Failed logins from one IP, for any email, before it is refused,
+    /// 0 for no limit
+    pub max_ip_failures: u32,
+    /// Seconds the first lock lasts, every failure after it doubles it
+    pub lockout_time: usize,
+    /// Longest a lock can last
+    pub max_lockout_time: usize,
+    /// Failures are forgotten this many seconds after the last one, or after
+    /// the lock they led to ends
+    pub failure_window: usize,
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        LoginLimits {
+            max_account_failures: 5,
+            max_ip_failures: 20,
+            lockout_time: 60,
+            max_lockout_time: 60 * 60,
+            failure_window: 15 * 60,
+        }
+    }
+}
+
+/// What [`check_rate_limit`] counts requests by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitBy {
+    Ip,
+    /// The user a request's token belongs to, requests without a valid
+    /// token are counted by IP
+    Token,
+    /// Both, a request is refused if either bucket is empty
+    IpAndToken,
+}
+
+impl FromStr for RateLimitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(' ', "").as_str() {
+            "ip" => Ok(RateLimitBy::Ip),
+            "token" => Ok(RateLimitBy::Token),
+            "ip,token" | "token,ip" => Ok(RateLimitBy::IpAndToken),
+            _ => Err(format!("unknown rate limit key {}", s)),
+        }
+    }
+}
+
+/// A token bucket every client gets, see [`Auth::rate_limit`]
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Requests a client can make at once, the size of its bucket
+    pub burst: u32,
+    /// Requests put back in the bucket every second, must be a finite
+    /// number above 0
+    pub per_second: f64,
+    pub by: RateLimitBy,
+}
+
 #[derive(Clone)]
 pub struct Auth {
-    redis: redis::Client,
-    postgres: Pool<Postgres>,
+    users: Arc<dyn UserStore>,
+    tokens: Arc<dyn TokenStore>,
+    mailer: Option<Arc<dyn Mailer>>,
     pub token_expire_time: Option<usize>,
+    pub hash_algorithm: HashAlgorithm,
+    pub jwt: Option<JwtConfig>,
+    pub refresh_token_expire_time: Option<usize>,
+    pub max_session_age: Option<usize>,
+    pub require_verified_email: bool,
+    pub totp_issuer: String,
+    pub webauthn: Option<WebauthnConfig>,
+    pub login_limits: LoginLimits,
+    pub rate_limit: Option<RateLimit>,
 }
 
 impl Auth {
+    pub fn new(users: impl UserStore + 'static, tokens: impl TokenStore + 'static) -> Self {
+        Auth {
+            users: Arc::new(users),
+            tokens: Arc::new(tokens),
+            mailer: None,
+            token_expire_time: None,
+            hash_algorithm: HashAlgorithm::default(),
+            jwt: None,
+            refresh_token_expire_time: None,
+            max_session_age: None,
+            require_verified_email: false,
+            totp_issuer: "passtoken".to_string(),
+            webauthn: None,
+            login_limits: LoginLimits::default(),
+            rate_limit: None,
+        }
+    }
+
     pub fn token_expire_time(mut self, token_expire_time: Option<usize>) -> Self {
         self.token_expire_time = token_expire_time;
         self
     }
+
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    /// Make `login` issue signed access tokens that can be verified without
+    /// a round trip to the token store. They last for `token_expire_time`
+    /// seconds, or [`DEFAULT_JWT_EXPIRE_TIME`] if it is not set, and are not
+    /// extended when used. Opaque tokens that were already issued keep working.
+    ///
+    /// Signing keys are kept in the user store, see [`load_signing_keys`]
+    /// and [`rotate_signing_key`].
+    pub fn jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Make `login` also issue refresh tokens lasting this many seconds,
+    /// which can be exchanged for new tokens with [`refresh_token`]. Access
+    /// tokens then last for `token_expire_time` seconds, or
+    /// [`DEFAULT_JWT_EXPIRE_TIME`] if it is not set, and are not extended
+    /// when used.
+    pub fn refresh_token_expire_time(mut self, refresh_token_expire_time: Option<usize>) -> Self {
+        self.refresh_token_expire_time = refresh_token_expire_time;
+        self
+    }
+
+    /// End every login this many seconds after it happened, no matter how
+    /// often its tokens are used or refreshed
+    pub fn max_session_age(mut self, max_session_age: Option<usize>) -> Self {
+        self.max_session_age = max_session_age;
+        self
+    }
+
+    /// Send a verification token to new users, and to users changing their
+    /// email, to be passed to [`verify_email`]
+    pub fn mailer(mut self, mailer: impl Mailer + 'static) -> Self {
+        self.mailer = Some(Arc::new(mailer));
+        self
+    }
+
+    /// Refuse to log in users that have not verified their email yet
+    pub fn require_verified_email(mut self, require_verified_email: bool) -> Self {
+        self.require_verified_email = require_verified_email;
+        self
+    }
+
+    /// Name authenticator apps show next to the codes for this service
+    pub fn totp_issuer(mut self, totp_issuer: String) -> Self {
+        self.totp_issuer = totp_issuer;
+        self
+    }
+
+    /// Let users register passkeys and log in with them, see
+    /// [`start_passkey_registration`] and [`start_passkey_login`]
+    pub fn webauthn(mut self, webauthn: WebauthnConfig) -> Self {
+        self.webauthn = Some(webauthn);
+        self
+    }
+
+    /// Lock emails and IPs with too many failed logins, including wrong
+    /// second factors. Locks are kept in the token store, so they hold for
+    /// every server sharing it.
+    pub fn login_limits(mut self, login_limits: LoginLimits) -> Self {
+        self.login_limits = login_limits;
+        self
+    }
+
+    /// Limit how often each client can make requests checked with
+    /// [`check_rate_limit`]. Buckets are kept in the token store, so the
+    /// limit holds across every server sharing it.
+    ///
+    /// Panics if `per_second` is not a finite number above 0.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        assert!(
+            rate_limit.per_second.is_finite() && rate_limit.per_second > 0.0,
+            "rate limit per_second must be a finite number above 0"
+        );
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// How long access tokens last when they are not extended when used
+    fn access_token_expire_time(&self) -> usize {
+        self.token_expire_time.unwrap_or(DEFAULT_JWT_EXPIRE_TIME)
+    }
 }
 
+/// Create an auth object that keeps users in postgres and tokens in redis.
+/// To namespace the redis keys, pass a [`RedisTokenStore`] with a prefix to
+/// [`Auth::new`] instead.
 pub async fn init_auth(postgres_url: String, redis_url: String) -> Result<Auth, AuthError> {
-    let postgres = util::get_pool(postgres_url).await?;
-    util::init_db(&postgres).await?;
-    let redis = redis::Client::open(redis_url).or_else(|x| Err(AuthError::RedisError(x)))?;
-    Ok(Auth {
-        redis,
-        postgres,
-        token_expire_time: None,
-    })
+    let users = PostgresUserStore::new(postgres_url).await?;
+    let tokens = RedisTokenStore::new(redis_url).await?;
+    Ok(Auth::new(users, tokens))
 }
 
-pub async fn create_user(
-    auth: &mut Auth,
-    email: String,
-    password: String,
-) -> Result<(), AuthError> {
+/// Create an auth object that keeps users in a sqlite database and tokens in
+/// memory, so it can run as a single process without any other services
+#[cfg(feature = "sqlite")]
+pub async fn init_sqlite_auth(sqlite_url: String) -> Result<Auth, AuthError> {
+    let users = crate::store::SqliteUserStore::new(sqlite_url).await?;
+    Ok(Auth::new(users, MemoryTokenStore::new()))
+}
+
+/// Create an auth object that keeps everything in memory, nothing is persisted
+pub fn init_memory_auth() -> Auth {
+    Auth::new(MemoryUserStore::new(), MemoryTokenStore::new())
+}
+
+pub async fn create_user(auth: &Auth, email: String, password: String) -> Result<(), AuthError> {
     // make sure email is not already in use
-    match get_user_by_email(auth, email.clone()).await {
+    match auth.users.get_user_by_email(email.clone()).await {
         Err(AuthError::UserDoesNotExist) => {}
-        Err(AuthError::PostgresError(dberr)) => return Err(AuthError::PostgresError(dberr)),
         Ok(_) => return Err(AuthError::UserAlreadyExists),
         Err(err) => return Err(err),
     };
 
-    // get hash and salt
-    let salt: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
-    let passwordhash = hash(password.clone(), salt.clone());
+    // get hash, the salt is stored inside of it
+    let passwordhash = auth.hash_algorithm.hash_password(password).await?;
 
     // create user
-    match sqlx::query!(
-        r#"
-        INSERT INTO users ( email, passwordhash, salt )
-        VALUES ( $1, $2, $3 );
-        "#,
-        email,
-        passwordhash,
-        salt
-    )
-    .execute(&auth.postgres)
-    .await
+    auth.users.create_user(email.clone(), passwordhash).await?;
+    let user = auth.users.get_user_by_email(email).await?;
+    // the user is removed again if the email can not be sent, rather than
+    // left without a way to verify it
+    if let Err(err) = send_verification_email(auth, &user).await {
+        auth.users.delete_user_by_email(user.email).await?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Mail a user a token proving they own their email, replacing any token
+/// sent before. Does nothing if there is no mailer.
+async fn send_verification_email(auth: &Auth, user: &User) -> Result<(), AuthError> {
+    let Some(mailer) = &auth.mailer else {
+        return Ok(());
+    };
+    let kind = EmailTokenKind::VerifyEmail;
+    auth.users.delete_email_tokens(user.id, kind).await?;
+    let token = random_token();
+    let expires_at = unix_now() + EMAIL_TOKEN_EXPIRE_TIME;
+    auth.users
+        .add_email_token(hash_token(&token), user.id, kind, expires_at)
+        .await?;
+    mailer
+        .send(
+            user.email.clone(),
+            "Verify your email address".to_string(),
+            format!("Use this token to verify your email address: {}", token),
+        )
+        .await
+}
+
+/// Mail a user a new verification token, for when the first one was lost or
+/// expired. Unknown and already verified emails are ignored so they can not
+/// be told apart from others.
+pub async fn resend_verification_email(auth: &Auth, email: String) -> Result<(), AuthError> {
+    if auth.mailer.is_none() {
+        return Err(AuthError::MailError("no mailer is configured".to_string()));
+    }
+    match auth.users.get_user_by_email(email).await {
+        Ok(user) if !user.email_verified => send_verification_email(auth, &user).await,
+        Ok(_) | Err(AuthError::UserDoesNotExist) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Mark the email of the user a verification token was sent to as verified.
+/// Each token works once.
+pub async fn verify_email(auth: &Auth, token: String) -> Result<(), AuthError> {
+    let kind = EmailTokenKind::VerifyEmail;
+    match auth
+        .users
+        .take_email_token(hash_token(&token), kind, unix_now())
+        .await?
     {
-        Ok(ok) => ok,
-        Err(err) => return Err(AuthError::PostgresError(err)),
+        Some(id) => auth.users.set_email_verified(id, true).await,
+        None => Err(AuthError::InvalidToken),
+    }
+}
+
+/// Mail a user a token for [`complete_password_reset`], replacing any token
+/// sent before. Unknown emails are ignored so they can not be told apart
+/// from registered ones.
+pub async fn request_password_reset(auth: &Auth, email: String) -> Result<(), AuthError> {
+    let Some(mailer) = &auth.mailer else {
+        return Err(AuthError::MailError("no mailer is configured".to_string()));
     };
-    Ok(())
+    let user = match auth.users.get_user_by_email(email).await {
+        Ok(user) => user,
+        Err(AuthError::UserDoesNotExist) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let kind = EmailTokenKind::PasswordReset;
+    auth.users.delete_email_tokens(user.id, kind).await?;
+    let token = random_token();
+    let expires_at = unix_now() + PASSWORD_RESET_EXPIRE_TIME;
+    auth.users
+        .add_email_token(hash_token(&token), user.id, kind, expires_at)
+        .await?;
+    mailer
+        .send(
+            user.email,
+            "Reset your password".to_string(),
+            format!("Use this token to reset your password: {}", token),
+        )
+        .await
 }
 
-async fn get_user_by_email(
+/// Set a new password with a token from [`request_password_reset`], ending
+/// every session of the user. Each token works once.
+pub async fn complete_password_reset(
+    auth: &Auth,
+    token: String,
+    password: String,
+) -> Result<(), AuthError> {
+    let kind = EmailTokenKind::PasswordReset;
+    let hash = hash_token(&token);
+    let Some(id) = auth
+        .users
+        .get_email_token(hash.clone(), kind, unix_now())
+        .await?
+    else {
+        return Err(AuthError::InvalidToken);
+    };
+    let user = auth.users.get_user_by_id(id).await?;
+    // the token is only used up once the password is set, so it can be
+    // tried again if that fails
+    update_user_by_email(auth, user.email.clone(), None, Some(password)).await?;
+    auth.users.take_email_token(hash, kind, unix_now()).await?;
+    // the token was mailed to them, so they own the address
+    auth.users.set_email_verified(id, true).await?;
+    auth.tokens
+        .clear_login_failures(account_failures_key(&user.email))
+        .await?;
+    delete_user_tokens(auth, user.email).await
+}
+
+/// Decode an access token, making sure it has not been logged out. Returns
+/// `None` if JWT mode is off or the token is not an access token.
+async fn get_claims(auth: &Auth, token: &str) -> Result<Option<Claims>, AuthError> {
+    let Some(config) = &auth.jwt else {
+        return Ok(None);
+    };
+    if !jwt::is_jwt(token) {
+        return Ok(None);
+    }
+    if config.should_reload(token) {
+        load_signing_keys(auth).await?;
+    }
+    let claims = jwt::decode(config, token)?;
+    if auth.tokens.is_token_denied(claims.jti.clone()).await? {
+        return Err(AuthError::InvalidToken);
+    }
+    if let Some(session) = &claims.sid {
+        if auth.tokens.is_token_denied(session.clone()).await? {
+            return Err(AuthError::InvalidToken);
+        }
+    }
+    Ok(Some(claims))
+}
+
+async fn get_id_from_token(auth: &Auth, token: String) -> Result<i32, AuthError> {
+    if let Some(claims) = get_claims(auth, &token).await? {
+        return claims.user_id();
+    }
+    match get_stored_token(auth, token).await? {
+        Some(token) => Ok(token.id),
+        None => Err(AuthError::TokenDoesNotExist),
+    }
+}
+
+/// Look up an opaque access token, strings that are not tokens are not
+/// looked up at all
+async fn get_stored_token(auth: &Auth, token: String) -> Result<Option<Token>, AuthError> {
+    if !is_token(&token) {
+        return Ok(None);
+    }
+    auth.tokens.get_token(token).await
+}
+
+/// Tokens handed out by `login` and `refresh_token`
+#[derive(Clone, Debug)]
+pub struct LoginTokens {
+    pub token: String,
+    /// Only issued if refresh tokens are enabled
+    pub refresh_token: Option<String>,
+}
+
+/// Where a login comes from, recorded with its session
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Name the client gives the device it logs in from
+    pub device: Option<String>,
+}
+
+/// What `login` hands out
+#[derive(Clone, Debug)]
+pub enum LoginResult {
+    Tokens(LoginTokens),
+    /// The user has TOTP enabled, the challenge is passed to [`login_mfa`]
+    /// along with a code to get the tokens
+    MfaRequired {
+        challenge: String,
+    },
+}
+
+pub async fn login(
     auth: &Auth,
     email: String,
-) -> Result<(i32, String, String, String), AuthError> {
-    let mut conn = connect(&auth.postgres).await?;
-    match sqlx::query!(
-        r#"
-        SELECT *
-        FROM users
-        WHERE email = $1;
-        "#,
-        email
-    )
-    .fetch_optional(&mut conn)
-    .await
+    password: String,
+    client: ClientInfo,
+) -> Result<LoginResult, AuthError> {
+    check_login_limits(auth, &email, client.ip.as_deref()).await?;
+    let verified = verify_user(auth, email.clone(), password.clone())
+        .await
+        .and_then(|x| match x {
+            true => Ok(()),
+            false => Err(AuthError::IncorrectUsernameOrPassword),
+        });
+    // guesses at unknown emails count as well
+    if let Err(AuthError::IncorrectUsernameOrPassword | AuthError::UserDoesNotExist) = verified {
+        add_login_failure(auth, &email, client.ip.as_deref()).await?;
+    }
+    verified?;
+    let user = auth.users.get_user_by_email(email).await?;
+    if auth.require_verified_email && !user.email_verified {
+        return Err(AuthError::EmailNotVerified);
+    }
+    if auth
+        .users
+        .get_totp(user.id)
+        .await?
+        .is_some_and(|x| x.confirmed)
     {
-        Ok(Some(user)) => Ok((user.id, user.email, user.passwordhash, user.salt)),
-        Ok(None) => Err(AuthError::UserDoesNotExist),
-        Err(err) => Err(AuthError::PostgresError(err)),
+        let challenge = random_token();
+        auth.tokens
+            .set_mfa_challenge(challenge.clone(), user.id, MFA_CHALLENGE_EXPIRE_TIME)
+            .await?;
+        return Ok(LoginResult::MfaRequired { challenge });
     }
+    auth.tokens
+        .clear_login_failures(account_failures_key(&user.email))
+        .await?;
+    start_session(auth, user, client)
+        .await
+        .map(LoginResult::Tokens)
 }
 
-async fn get_user_by_id(auth: &Auth, id: i32) -> Result<(i32, String, String, String), AuthError> {
-    let mut conn = connect(&auth.postgres).await?;
-    match sqlx::query!(
-        r#"
-        SELECT *
-        FROM users
-        WHERE id = $1;
-        "#,
-        id
-    )
-    .fetch_optional(&mut conn)
-    .await
-    {
-        Ok(Some(user)) => Ok((user.id, user.email, user.passwordhash, user.salt)),
-        Ok(None) => Err(AuthError::UserDoesNotExist),
-        Err(err) => Err(AuthError::PostgresError(err)),
+/// Finish a login that `login` asked a second factor for, by answering its
+/// challenge with a code from the user's authenticator app or one of their
+/// recovery codes. A challenge only takes a few wrong codes.
+pub async fn login_mfa(
+    auth: &Auth,
+    challenge: String,
+    code: String,
+    client: ClientInfo,
+) -> Result<LoginTokens, AuthError> {
+    let Some(stored) = auth.tokens.use_mfa_challenge(challenge.clone()).await? else {
+        return Err(AuthError::InvalidToken);
+    };
+    if stored.attempts > MAX_MFA_ATTEMPTS {
+        auth.tokens.delete_mfa_challenge(challenge).await?;
+        return Err(AuthError::InvalidToken);
+    }
+    let user = auth.users.get_user_by_id(stored.id).await?;
+    check_login_limits(auth, &user.email, client.ip.as_deref()).await?;
+    let totp = auth.users.get_totp(user.id).await?;
+    if !check_second_factor(auth, user.id, totp, &code).await? {
+        if stored.attempts >= MAX_MFA_ATTEMPTS {
+            auth.tokens.delete_mfa_challenge(challenge).await?;
+        }
+        // otherwise someone with the password could keep starting new challenges
+        add_login_failure(auth, &user.email, client.ip.as_deref()).await?;
+        return Err(AuthError::IncorrectMfaCode);
     }
+    auth.tokens.delete_mfa_challenge(challenge).await?;
+    auth.tokens
+        .clear_login_failures(account_failures_key(&user.email))
+        .await?;
+    start_session(auth, user, client).await
 }
 
-async fn update_user_by_email(
+fn account_failures_key(email: &str) -> String {
+    format!("account:{}", email)
+}
+
+fn ip_failures_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Refuse to try a login for an email or IP that is locked
+async fn check_login_limits(auth: &Auth, email: &str, ip: Option<&str>) -> Result<(), AuthError> {
+    let now = unix_now();
+    let remaining = |x: LoginFailures| x.locked_until.filter(|x| *x > now).map(|x| x - now);
+    let limits = &auth.login_limits;
+    if limits.max_account_failures > 0 {
+        let failures = auth
+            .tokens
+            .get_login_failures(account_failures_key(email))
+            .await?;
+        if let Some(x) = remaining(failures) {
+            return Err(AuthError::AccountLocked(x));
+        }
+    }
+    if let Some(ip) = ip.filter(|_| limits.max_ip_failures > 0) {
+        let failures = auth.tokens.get_login_failures(ip_failures_key(ip)).await?;
+        if let Some(x) = remaining(failures) {
+            return Err(AuthError::TooManyAttempts(x));
+        }
+    }
+    Ok(())
+}
+
+/// Count a failed login against its email and IP, locking them once they
+/// reach their limit. Each failure after that doubles the lock.
+async fn add_login_failure(auth: &Auth, email: &str, ip: Option<&str>) -> Result<(), AuthError> {
+    let limits = &auth.login_limits;
+    let mut keys = Vec::new();
+    if limits.max_account_failures > 0 {
+        keys.push((account_failures_key(email), limits.max_account_failures));
+    }
+    if let Some(ip) = ip.filter(|_| limits.max_ip_failures > 0) {
+        keys.push((ip_failures_key(ip), limits.max_ip_failures));
+    }
+    for (key, max) in keys {
+        let count = auth
+            .tokens
+            .add_login_failure(key.clone(), limits.failure_window)
+            .await?;
+        if count < max {
+            continue;
+        }
+        let lockout = 2usize
+            .checked_pow(count - max)
+            .map_or(usize::MAX, |x| x.saturating_mul(limits.lockout_time))
+            .min(limits.max_lockout_time);
+        auth.tokens
+            .lock_login(
+                key,
+                unix_now() + lockout as u64,
+                lockout + limits.failure_window,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Start a session for a user that proved who they are
+async fn start_session(
     auth: &Auth,
-    filter: String,
-    email: Option<String>,
-    password: Option<String>,
-) -> Result<(), AuthError> {
-    let mut conn = connect(&auth.postgres).await?;
+    user: User,
+    client: ClientInfo,
+) -> Result<LoginTokens, AuthError> {
+    let now = unix_now();
+    let session = Session {
+        id: random_token(),
+        user_id: user.id,
+        created_at: now,
+        last_used_at: now,
+        ip: client.ip,
+        user_agent: client.user_agent,
+        device: client.device,
+    };
+    let id = session.id.clone();
+    auth.tokens.create_session(session).await?;
+    let session_expires_at = auth.max_session_age.map(|x| now + x as u64);
+    issue_tokens(auth, user, id, session_expires_at).await
+}
 
-    // Check if user exists
-    match get_user_by_email(auth, filter.clone()).await {
-        Ok(_) => {}
-        Err(AuthError::UserDoesNotExist) => return Err(AuthError::UserDoesNotExist),
-        Err(AuthError::PostgresError(err)) => return Err(AuthError::PostgresError(err)),
-        _ => unreachable!(),
+/// Check a code from the user's authenticator app, each code is only
+/// accepted once
+async fn check_totp(
+    auth: &Auth,
+    id: i32,
+    totp: Option<Totp>,
+    code: &str,
+) -> Result<bool, AuthError> {
+    let Some(totp) = totp else {
+        return Ok(false);
     };
+    match totp::verify(&totp.secret, code, unix_now(), totp.last_step) {
+        Some(step) => auth.users.use_totp_step(id, step).await,
+        None => Ok(false),
+    }
+}
 
-    if let Some(email) = email.clone() {
-        match sqlx::query!(
-            r#"
-            UPDATE "users"
-            SET email   = $1
-            WHERE email = $2;
-            "#,
-            email,
-            filter
-        )
-        .execute(&mut conn)
+/// Check a code from the user's authenticator app, or else one of their
+/// recovery codes, which is used up
+async fn check_second_factor(
+    auth: &Auth,
+    id: i32,
+    totp: Option<Totp>,
+    code: &str,
+) -> Result<bool, AuthError> {
+    if check_totp(auth, id, totp, code).await? {
+        return Ok(true);
+    }
+    auth.users
+        .take_recovery_code(id, hash_recovery_code(code))
         .await
-        {
-            Ok(ok) => ok,
-            Err(err) => return Err(AuthError::PostgresError(err)),
-        };
+}
+
+/// Recovery codes are hashed without the dash, and with any case, so they
+/// can be typed either way
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_lowercase())
+        .collect();
+    hash_token(&code)
+}
+
+/// Give a user a new set of recovery codes, replacing the ones they had
+async fn new_recovery_codes(auth: &Auth, id: i32) -> Result<Vec<String>, AuthError> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| random_recovery_code())
+        .collect();
+    let hashes = codes.iter().map(|x| hash_recovery_code(x)).collect();
+    auth.users.set_recovery_codes(id, hashes).await?;
+    Ok(codes)
+}
+
+/// A TOTP secret for the user to add to their authenticator app
+#[derive(Clone, Debug)]
+pub struct TotpEnrollment {
+    /// Base32 encoded, for entering by hand
+    pub secret: String,
+    /// `otpauth://` URI holding the secret, usually shown as a QR code
+    pub uri: String,
+}
+
+/// Start setting up TOTP for the token's user, replacing a secret that was
+/// not confirmed yet. Logins only ask for codes once one is passed to
+/// [`confirm_totp`].
+pub async fn enroll_totp(auth: &Auth, token: String) -> Result<TotpEnrollment, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let user = auth.users.get_user_by_id(id).await?;
+    if auth.users.get_totp(id).await?.is_some_and(|x| x.confirmed) {
+        return Err(AuthError::TotpAlreadyEnabled);
+    }
+    let secret = totp::generate_secret();
+    auth.users.set_totp(id, secret.clone()).await?;
+    Ok(TotpEnrollment {
+        uri: totp::provisioning_uri(&auth.totp_issuer, &user.email, &secret),
+        secret,
+    })
+}
+
+/// Turn on TOTP for the token's user with a code proving their app has the
+/// secret from [`enroll_totp`]. Returns recovery codes that each work once
+/// in place of a code, for when the app is lost. They are only shown now.
+pub async fn confirm_totp(
+    auth: &Auth,
+    token: String,
+    code: String,
+) -> Result<Vec<String>, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let totp = match auth.users.get_totp(id).await? {
+        Some(x) if x.confirmed => return Err(AuthError::TotpAlreadyEnabled),
+        Some(x) => x,
+        None => return Err(AuthError::TotpNotEnabled),
     };
+    if !check_totp(auth, id, Some(totp), &code).await? {
+        return Err(AuthError::IncorrectMfaCode);
+    }
+    auth.users.confirm_totp(id).await?;
+    new_recovery_codes(auth, id).await
+}
 
-    if let Some(password) = password {
-        // get hash and salt
-        let salt: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(7)
-            .map(char::from)
-            .collect();
-        let passwordhash = hash(password.clone(), salt.clone());
-        match sqlx::query!(
-            r#"
-            UPDATE "users"
-            SET passwordhash = $1,
-                salt         = $2
-            WHERE email      = $3;
-            "#,
-            passwordhash,
-            salt,
-            filter
-        )
-        .execute(&mut conn)
-        .await
-        {
-            Ok(ok) => ok,
-            Err(err) => return Err(AuthError::PostgresError(err)),
-        };
+/// Turn off TOTP for the token's user, which takes a current code or a
+/// recovery code once it is enabled. Their recovery codes are deleted.
+pub async fn disable_totp(auth: &Auth, token: String, code: String) -> Result<(), AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let totp = match auth.users.get_totp(id).await? {
+        Some(x) => x,
+        None => return Err(AuthError::TotpNotEnabled),
     };
+    if totp.confirmed && !check_second_factor(auth, id, Some(totp), &code).await? {
+        return Err(AuthError::IncorrectMfaCode);
+    }
+    auth.users.set_recovery_codes(id, Vec::new()).await?;
+    auth.users.delete_totp(id).await
+}
 
-    Ok(())
+/// Replace the recovery codes of the token's user with new ones, for when
+/// they ran low or the old ones may have leaked
+pub async fn regenerate_recovery_codes(
+    auth: &Auth,
+    token: String,
+) -> Result<Vec<String>, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    if !auth.users.get_totp(id).await?.is_some_and(|x| x.confirmed) {
+        return Err(AuthError::TotpNotEnabled);
+    }
+    new_recovery_codes(auth, id).await
 }
 
-async fn delete_user_by_email(auth: &Auth, filter: String) -> Result<(), AuthError> {
-    let mut conn = connect(&auth.postgres).await?;
+/// How many unused recovery codes the token's user has left
+pub async fn count_recovery_codes(auth: &Auth, token: String) -> Result<usize, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    auth.users.count_recovery_codes(id).await
+}
 
-    // make sure user exists
-    get_user_by_email(auth, filter.clone()).await?;
+fn webauthn_config(auth: &Auth) -> Result<&WebauthnConfig, AuthError> {
+    auth.webauthn
+        .as_ref()
+        .ok_or_else(|| AuthError::WebauthnError("webauthn is not enabled".to_string()))
+}
 
-    match sqlx::query!(
-        r#"
-        DELETE FROM "users"
-        WHERE email = $1;
-        "#,
-        filter
-    )
-    .execute(&mut conn)
-    .await
-    {
-        Ok(_) => {}
-        Err(err) => return Err(AuthError::PostgresError(err)),
+/// Start registering a passkey for the token's user, returning the options
+/// to pass to `navigator.credentials.create()`
+pub async fn start_passkey_registration(
+    auth: &Auth,
+    token: String,
+) -> Result<serde_json::Value, AuthError> {
+    let config = webauthn_config(auth)?;
+    let id = get_id_from_token(auth, token).await?;
+    let user = auth.users.get_user_by_id(id).await?;
+    let challenge = webauthn::generate_challenge();
+    let state = WebauthnChallenge {
+        ceremony: WebauthnCeremony::Registration,
+        user_id: Some(id),
     };
+    auth.tokens
+        .set_webauthn_challenge(challenge.clone(), state, WEBAUTHN_CHALLENGE_EXPIRE_TIME)
+        .await?;
+    let existing = auth.users.get_user_passkeys(id).await?;
+    Ok(webauthn::creation_options(
+        config,
+        &user,
+        &challenge,
+        WEBAUTHN_CHALLENGE_EXPIRE_TIME,
+        &existing,
+    ))
+}
 
-    Ok(())
+/// Store the passkey `navigator.credentials.create()` created for the
+/// token's user, under an optional name
+pub async fn finish_passkey_registration(
+    auth: &Auth,
+    token: String,
+    credential: RegistrationCredential,
+    name: Option<String>,
+) -> Result<Passkey, AuthError> {
+    let config = webauthn_config(auth)?;
+    let id = get_id_from_token(auth, token).await?;
+    let (challenge, new) = webauthn::verify_registration(config, &credential)?;
+    match auth.tokens.take_webauthn_challenge(challenge).await? {
+        Some(WebauthnChallenge {
+            ceremony: WebauthnCeremony::Registration,
+            user_id: Some(x),
+        }) if x == id => {}
+        _ => return Err(AuthError::InvalidPasskey("unknown challenge".to_string())),
+    }
+    if auth.users.get_passkey(new.id.clone()).await?.is_some() {
+        return Err(AuthError::InvalidPasskey(
+            "passkey is already registered".to_string(),
+        ));
+    }
+    let passkey = Passkey {
+        id: new.id,
+        user_id: id,
+        public_key: new.public_key,
+        algorithm: new.algorithm,
+        sign_count: new.sign_count,
+        name,
+        created_at: unix_now(),
+        last_used_at: None,
+    };
+    auth.users.add_passkey(passkey.clone()).await?;
+    Ok(passkey)
 }
 
-fn get_id_from_token(auth: &mut Auth, token: String) -> Result<i32, AuthError> {
-    auth.redis
-        .get(&token) // get id from token
-        .or_else(|x| Err(AuthError::RedisError(x)))
-        .and_then(|id: Option<i32>| {
-            if let Some(id) = id {
-                Ok(id)
-            } else {
-                Err(AuthError::TokenDoesNotExist)
-            }
-        })
-}
-
-pub async fn login(auth: &mut Auth, email: String, password: String) -> Result<String, AuthError> {
-    if !verify_user(auth, email.clone(), password.clone()).await? {
-        return Err(AuthError::IncorrectUsernameOrPassword);
-    }
-    let token = generate_token(&mut auth.redis)?;
-    let (id, _, _, _) = get_user_by_email(auth, email).await?;
-    let _ = auth
-        .redis
-        .set(token.clone(), id)
-        .or_else(|x| Err(AuthError::RedisError(x)))?;
-    match auth.token_expire_time {
-        Some(x) => {
-            let _ = auth
-                .redis
-                .expire(token.clone(), x)
-                .or_else(|x| Err(AuthError::RedisError(x)))?;
+/// Start logging in with a passkey, returning the options to pass to
+/// `navigator.credentials.get()`. Given an email only the passkeys of that
+/// user are offered, otherwise the authenticator offers any it has for the
+/// site.
+pub async fn start_passkey_login(
+    auth: &Auth,
+    email: Option<String>,
+) -> Result<serde_json::Value, AuthError> {
+    let config = webauthn_config(auth)?;
+    let (user_id, allowed) = match email {
+        Some(email) => match auth.users.get_user_by_email(email).await {
+            Ok(user) => (Some(user.id), auth.users.get_user_passkeys(user.id).await?),
+            // unknown emails look the same as no email
+            Err(AuthError::UserDoesNotExist) => (None, Vec::new()),
+            Err(err) => return Err(err),
+        },
+        None => (None, Vec::new()),
+    };
+    let challenge = webauthn::generate_challenge();
+    let state = WebauthnChallenge {
+        ceremony: WebauthnCeremony::Authentication,
+        user_id,
+    };
+    auth.tokens
+        .set_webauthn_challenge(challenge.clone(), state, WEBAUTHN_CHALLENGE_EXPIRE_TIME)
+        .await?;
+    Ok(webauthn::request_options(
+        config,
+        &challenge,
+        WEBAUTHN_CHALLENGE_EXPIRE_TIME,
+        &allowed,
+    ))
+}
+
+/// Log in with what `navigator.credentials.get()` resolved to, issuing the
+/// same tokens as [`login`]. Authenticators verify the user themselves, so
+/// no second factor is asked for.
+pub async fn finish_passkey_login(
+    auth: &Auth,
+    credential: LoginCredential,
+    client: ClientInfo,
+) -> Result<LoginTokens, AuthError> {
+    let config = webauthn_config(auth)?;
+    let id = webauthn::canonical(&credential.id)?;
+    let Some(passkey) = auth.users.get_passkey(id).await? else {
+        return Err(AuthError::InvalidPasskey("unknown passkey".to_string()));
+    };
+    let (challenge, sign_count) = webauthn::verify_assertion(config, &credential, &passkey)?;
+    match auth.tokens.take_webauthn_challenge(challenge).await? {
+        Some(WebauthnChallenge {
+            ceremony: WebauthnCeremony::Authentication,
+            user_id,
+        }) if user_id.is_none_or(|x| x == passkey.user_id) => {}
+        _ => return Err(AuthError::InvalidPasskey("unknown challenge".to_string())),
+    }
+    auth.users
+        .update_passkey_use(passkey.id, sign_count, unix_now())
+        .await?;
+    let user = auth.users.get_user_by_id(passkey.user_id).await?;
+    if auth.require_verified_email && !user.email_verified {
+        return Err(AuthError::EmailNotVerified);
+    }
+    start_session(auth, user, client).await
+}
+
+/// Every passkey of the token's user, oldest first
+pub async fn list_passkeys(auth: &Auth, token: String) -> Result<Vec<Passkey>, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    auth.users.get_user_passkeys(id).await
+}
+
+pub async fn delete_passkey(auth: &Auth, token: String, passkey: String) -> Result<(), AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    if auth.users.delete_passkey(id, passkey).await? {
+        Ok(())
+    } else {
+        Err(AuthError::PasskeyDoesNotExist)
+    }
+}
+
+/// Shorten an expire time so it does not outlast the session
+fn cap_expire(expire: usize, session_expires_at: Option<u64>) -> usize {
+    match session_expires_at {
+        // redis refuses an expire time of zero
+        Some(x) => expire.min(x.saturating_sub(unix_now()).max(1) as usize),
+        None => expire,
+    }
+}
+
+/// Issue an access token in a session, along with a refresh token if they
+/// are enabled, and keep the session around for as long as they last
+async fn issue_tokens(
+    auth: &Auth,
+    user: User,
+    session: String,
+    session_expires_at: Option<u64>,
+) -> Result<LoginTokens, AuthError> {
+    let refresh_token = match auth.refresh_token_expire_time {
+        Some(expire) => {
+            let token = random_token();
+            let expire = cap_expire(expire, session_expires_at);
+            auth.tokens
+                .set_refresh_token(
+                    token.clone(),
+                    user.id,
+                    session.clone(),
+                    session_expires_at,
+                    expire,
+                )
+                .await?;
+            Some((token, expire))
+        }
+        None => None,
+    };
+    let (token, expire) = match &auth.jwt {
+        Some(config) => {
+            let expire = cap_expire(auth.access_token_expire_time(), session_expires_at);
+            let token = jwt::issue(config, user.id, user.email, Some(session.clone()), expire)?;
+            (token, Some(expire))
         }
-        None => {}
+        None => {
+            let token = generate_token(auth.tokens.as_ref()).await?;
+            let expire = match refresh_token {
+                Some(_) => Some(auth.access_token_expire_time()),
+                None => auth.token_expire_time,
+            };
+            // tokens that never expire still end with the session
+            let expire = expire
+                .or(session_expires_at.map(|_| usize::MAX))
+                .map(|x| cap_expire(x, session_expires_at));
+            auth.tokens
+                .set_token(
+                    token.clone(),
+                    user.id,
+                    Some(session.clone()),
+                    session_expires_at,
+                    expire,
+                )
+                .await?;
+            (token, expire)
+        }
+    };
+    // refresh tokens outlive access tokens
+    let expire = refresh_token.as_ref().map(|(_, x)| *x).or(expire);
+    auth.tokens.touch_session(session, expire).await?;
+    Ok(LoginTokens {
+        token,
+        refresh_token: refresh_token.map(|(token, _)| token),
+    })
+}
+
+/// Exchange a refresh token for a new access token and refresh token. Each
+/// refresh token can only be used once, using one again ends the session it
+/// was issued in, as it has likely been stolen.
+pub async fn refresh_token(auth: &Auth, refresh_token: String) -> Result<LoginTokens, AuthError> {
+    if !is_token(&refresh_token) {
+        return Err(AuthError::TokenDoesNotExist);
+    }
+    let Some(stored) = auth.tokens.use_refresh_token(refresh_token).await? else {
+        return Err(AuthError::TokenDoesNotExist);
+    };
+    if stored.used {
+        end_session(auth, stored.session).await?;
+        return Err(AuthError::InvalidToken);
     }
-    Ok(token)
+    let user = auth.users.get_user_by_id(stored.id).await?;
+    issue_tokens(auth, user, stored.session, stored.session_expires_at).await
 }
 
-pub fn logout(auth: &mut Auth, token: String) -> Result<(), AuthError> {
-    match auth
-        .redis
-        .del::<_, Option<i32>>(&token)
-        .or_else(|x| Err(AuthError::RedisError(x)))?
+/// Delete a session and every token issued in it, denying its access tokens
+/// until the last of them would have expired
+async fn end_session(auth: &Auth, session: String) -> Result<(), AuthError> {
+    if auth.jwt.is_some() {
+        let expire = auth.access_token_expire_time();
+        auth.tokens.deny_token(session.clone(), expire).await?;
+    }
+    auth.tokens.delete_session(session).await
+}
+
+/// Ends the session the token was issued in. Access tokens issued before
+/// sessions were recorded are denied until they expire, opaque ones are
+/// deleted.
+pub async fn logout(auth: &Auth, token: String) -> Result<(), AuthError> {
+    if let Some(claims) = get_claims(auth, &token).await? {
+        if let Some(session) = claims.sid {
+            return end_session(auth, session).await;
+        }
+        // redis refuses an expire time of zero
+        let expire = claims.exp.saturating_sub(unix_now()).max(1);
+        return auth.tokens.deny_token(claims.jti, expire as usize).await;
+    }
+    if !is_token(&token) {
+        return Err(AuthError::TokenDoesNotExist);
+    }
+    if let Some(Token {
+        session: Some(session),
+        ..
+    }) = auth.tokens.get_token(token.clone()).await?
     {
-        Some(_) => Ok(()),
-        None => Err(AuthError::TokenDoesNotExist),
+        return end_session(auth, session).await;
+    }
+    if auth.tokens.delete_token(token).await? {
+        Ok(())
+    } else {
+        Err(AuthError::TokenDoesNotExist)
     }
 }
 
-async fn delete_user_tokens(auth: &mut Auth, email: String) -> Result<(), AuthError> {
-    let (id, _, _, _) = get_user_by_email(auth, email.clone()).await?;
-    let keys: Vec<String> = auth
-        .redis
-        .keys("*")
-        .or_else(|x| Err(AuthError::RedisError(x)))?;
-    for x in keys {
-        match auth.redis.get::<_, i32>(x.clone()) {
-            Ok(y) => {
-                if id == y {
-                    match auth.redis.del::<_, i32>(x) {
-                        Ok(_) => {}
-                        Err(x) => {
-                            return Err(AuthError::RedisError(x));
-                        }
-                    };
-                }
-            }
-            Err(_) => {}
+async fn delete_user_tokens(auth: &Auth, email: String) -> Result<(), AuthError> {
+    let user = auth.users.get_user_by_email(email).await?;
+    if auth.jwt.is_some() {
+        let expire = auth.access_token_expire_time();
+        for session in auth.tokens.get_user_sessions(user.id).await? {
+            auth.tokens.deny_token(session.id, expire).await?;
         }
     }
-    Ok(())
+    auth.tokens.delete_user_tokens(user.id).await
 }
 
 pub async fn update_user(
-    auth: &mut Auth,
+    auth: &Auth,
     token: String,
     new_email: Option<String>,
     new_password: Option<String>,
     logout: bool,
 ) -> Result<(), AuthError> {
-    let id = get_id_from_token(auth, token)?;
-    let (_, email, _, _) = get_user_by_id(auth, id).await?;
+    let id = get_id_from_token(auth, token).await?;
+    let user = auth.users.get_user_by_id(id).await?;
     if logout {
-        delete_user_tokens(auth, email.clone()).await?;
+        delete_user_tokens(auth, user.email.clone()).await?;
+    }
+    update_user_by_email(auth, user.email, new_email, new_password).await
+}
+
+/// Get the user a token belongs to, making sure they are an admin
+async fn require_admin(auth: &Auth, token: String) -> Result<User, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let user = auth.users.get_user_by_id(id).await?;
+    if !user.is_admin {
+        return Err(AuthError::PermissionDenied);
+    }
+    Ok(user)
+}
+
+/// Make sure an admin account exists, for bootstrapping a fresh install.
+/// Creates the user if needed, an existing user is only promoted if the
+/// password matches. Does nothing once the user is an admin, so a password
+/// changed since does not stop the server from starting.
+pub async fn create_admin(auth: &Auth, email: String, password: String) -> Result<(), AuthError> {
+    match auth.users.get_user_by_email(email.clone()).await {
+        Ok(user) if user.is_admin => return Ok(()),
+        Ok(_) | Err(AuthError::UserDoesNotExist) => {}
+        Err(err) => return Err(err),
     }
-    update_user_by_email(auth, email, new_email, new_password).await
+    match create_user(auth, email.clone(), password.clone()).await {
+        Ok(_) => {}
+        Err(AuthError::UserAlreadyExists) => {
+            if !verify_user(auth, email.clone(), password).await? {
+                return Err(AuthError::IncorrectUsernameOrPassword);
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    let user = auth.users.get_user_by_email(email.clone()).await?;
+    auth.users.set_email_verified(user.id, true).await?;
+    auth.users.set_admin(email, true).await
+}
+
+pub async fn set_admin(
+    auth: &Auth,
+    token: String,
+    filter: String,
+    is_admin: bool,
+) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    auth.users.set_admin(filter, is_admin).await
 }
 
 pub async fn admin_update_user(
-    auth: &mut Auth,
+    auth: &Auth,
+    token: String,
     filter: String,
     email: Option<String>,
     password: Option<String>,
     logout: bool,
 ) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
     if logout {
         delete_user_tokens(auth, filter.clone()).await?;
     }
     update_user_by_email(auth, filter, email, password).await
 }
 
-pub async fn delete_user(auth: &mut Auth, token: String) -> Result<(), AuthError> {
-    let id = get_id_from_token(auth, token)?;
-    let (_, email, _, _) = get_user_by_id(auth, id).await?;
-    delete_user_tokens(auth, email.clone()).await?;
-    delete_user_by_email(auth, email).await
+async fn update_user_by_email(
+    auth: &Auth,
+    filter: String,
+    email: Option<String>,
+    password: Option<String>,
+) -> Result<(), AuthError> {
+    let passwordhash = match password {
+        Some(password) => Some(auth.hash_algorithm.hash_password(password).await?),
+        None => None,
+    };
+    let new_email = email.clone().filter(|x| *x != filter);
+    auth.users
+        .update_user_by_email(filter, email, passwordhash)
+        .await?;
+    // the new address has to be verified again
+    match new_email {
+        Some(email) => {
+            let user = auth.users.get_user_by_email(email).await?;
+            send_verification_email(auth, &user).await
+        }
+        None => Ok(()),
+    }
 }
 
-pub async fn admin_delete_user(auth: &mut Auth, filter: String) -> Result<(), AuthError> {
+pub async fn delete_user(auth: &Auth, token: String) -> Result<(), AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let user = auth.users.get_user_by_id(id).await?;
+    delete_user_tokens(auth, user.email.clone()).await?;
+    auth.users.delete_user_by_email(user.email).await
+}
+
+pub async fn admin_delete_user(
+    auth: &Auth,
+    token: String,
+    filter: String,
+) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
     delete_user_tokens(auth, filter.clone()).await?;
-    delete_user_by_email(auth, filter).await
+    auth.users.delete_user_by_email(filter).await
 }
 
-async fn verify_user(auth: &mut Auth, email: String, password: String) -> Result<bool, AuthError> {
-    let (_, _, passwordhash, salt) = get_user_by_email(auth, email).await?;
-    if hash(password, salt) == passwordhash {
-        Ok(true)
-    } else {
-        Ok(false)
+/// Let an email log in again after too many failed logins
+pub async fn unlock_user(auth: &Auth, token: String, filter: String) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    auth.tokens
+        .clear_login_failures(account_failures_key(&filter))
+        .await
+}
+
+/// Let an IP log in again after too many failed logins
+pub async fn unlock_ip(auth: &Auth, token: String, ip: String) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    auth.tokens.clear_login_failures(ip_failures_key(&ip)).await
+}
+
+/// Count a request from `ip` carrying `token` against the rate limit,
+/// refusing it with [`AuthError::TooManyRequests`] once the client's bucket is
+/// empty. Every request is let through if no rate limit is set.
+pub async fn check_rate_limit(
+    auth: &Auth,
+    ip: Option<String>,
+    token: Option<String>,
+) -> Result<(), AuthError> {
+    let Some(limit) = &auth.rate_limit else {
+        return Ok(());
+    };
+    // made up tokens would get a fresh bucket each, so only valid ones count
+    let user = match (limit.by, token) {
+        (RateLimitBy::Ip, _) | (_, None) => None,
+        (_, Some(token)) => match get_id_from_token(auth, token).await {
+            Ok(id) => Some(id),
+            Err(AuthError::InvalidToken | AuthError::TokenDoesNotExist) => None,
+            Err(err) => return Err(err),
+        },
+    };
+    let mut keys = Vec::new();
+    if let Some(ip) = ip.filter(|_| limit.by != RateLimitBy::Token || user.is_none()) {
+        keys.push(format!("ip:{}", ip));
+    }
+    if let Some(id) = user {
+        keys.push(format!("user:{}", id));
+    }
+    for key in keys {
+        let wait = auth
+            .tokens
+            .take_rate_limit(key, limit.burst, limit.per_second)
+            .await?;
+        if let Some(x) = wait {
+            return Err(AuthError::TooManyRequests(x.div_ceil(1000)));
+        }
+    }
+    Ok(())
+}
+
+async fn verify_user(auth: &Auth, email: String, password: String) -> Result<bool, AuthError> {
+    let user = auth.users.get_user_by_email(email.clone()).await?;
+    if !password::verify_password(password.clone(), user.passwordhash.clone(), user.salt).await? {
+        return Ok(false);
+    }
+    // upgrade legacy or outdated hashes now that we know the password
+    if auth.hash_algorithm.needs_rehash(&user.passwordhash) {
+        update_user_by_email(auth, email, None, Some(password)).await?;
+    }
+    Ok(true)
+}
+
+/// The user a token belongs to, along with what they are allowed to do.
+/// Times are in unix seconds.
+#[derive(Clone, Debug)]
+pub struct UserInfo {
+    pub id: i32,
+    pub email: String,
+    pub email_verified: bool,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    /// Id of the session the token was issued in, unknown for tokens issued
+    /// before sessions were recorded
+    pub session: Option<String>,
+    /// Unknown for tokens issued before issue times were recorded
+    pub issued_at: Option<u64>,
+    /// `None` if the token does not expire
+    pub expires_at: Option<u64>,
+}
+
+/// Returns `None` if the token is invalid or expired
+pub async fn verify_token(auth: &Auth, token: String) -> Result<Option<UserInfo>, AuthError> {
+    match get_claims(auth, &token).await {
+        Ok(Some(claims)) => {
+            let id = claims.user_id()?;
+            if let Some(session) = &claims.sid {
+                auth.tokens.touch_session(session.clone(), None).await?;
+            }
+            return user_info(auth, id, claims.sid, Some(claims.iat), Some(claims.exp))
+                .await
+                .map(Some);
+        }
+        Ok(None) => {}
+        Err(AuthError::InvalidToken) => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let Some(stored) = get_stored_token(auth, token.clone()).await? else {
+        return Ok(None);
+    };
+    if stored.session_expires_at.is_some_and(|x| x <= unix_now()) {
+        return Ok(None);
+    }
+    let mut expires_at = stored.expires_at;
+    let mut expire = None;
+    // tokens have a fixed lifetime when they can be refreshed
+    if let (Some(x), None) = (auth.token_expire_time, auth.refresh_token_expire_time) {
+        let x = cap_expire(x, stored.session_expires_at);
+        auth.tokens.expire_token(token, x).await?;
+        expires_at = Some(unix_now() + x as u64);
+        expire = Some(x);
+    }
+    if let Some(session) = &stored.session {
+        auth.tokens.touch_session(session.clone(), expire).await?;
+    }
+    user_info(
+        auth,
+        stored.id,
+        stored.session,
+        stored.issued_at,
+        expires_at,
+    )
+    .await
+    .map(Some)
+}
+
+async fn user_info(
+    auth: &Auth,
+    id: i32,
+    session: Option<String>,
+    issued_at: Option<u64>,
+    expires_at: Option<u64>,
+) -> Result<UserInfo, AuthError> {
+    let user = auth.users.get_user_by_id(id).await?;
+    Ok(UserInfo {
+        id: user.id,
+        email: user.email,
+        email_verified: user.email_verified,
+        roles: auth.users.get_roles(user.id).await?,
+        permissions: auth.users.get_permissions(user.id).await?,
+        session,
+        issued_at,
+        expires_at,
+    })
+}
+
+/// Check if any of the roles of the token's user grant a permission
+pub async fn has_permission(
+    auth: &Auth,
+    token: String,
+    permission: String,
+) -> Result<bool, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    Ok(auth.users.get_permissions(id).await?.contains(&permission))
+}
+
+/// Create a role, or replace the permissions of an existing one
+pub async fn create_role(
+    auth: &Auth,
+    token: String,
+    role: String,
+    permissions: Vec<String>,
+) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    auth.users.set_role(role, permissions).await
+}
+
+pub async fn delete_role(auth: &Auth, token: String, role: String) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    auth.users.delete_role(role).await
+}
+
+pub async fn assign_role(
+    auth: &Auth,
+    token: String,
+    filter: String,
+    role: String,
+) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    let user = auth.users.get_user_by_email(filter).await?;
+    auth.users.assign_role(user.id, role).await
+}
+
+pub async fn revoke_role(
+    auth: &Auth,
+    token: String,
+    filter: String,
+    role: String,
+) -> Result<(), AuthError> {
+    require_admin(auth, token).await?;
+    let user = auth.users.get_user_by_email(filter).await?;
+    auth.users.revoke_role(user.id, role).await
+}
+
+fn jwt_config(auth: &Auth) -> Result<&JwtConfig, AuthError> {
+    auth.jwt
+        .as_ref()
+        .ok_or_else(|| AuthError::JwtError("jwt mode is not enabled".to_string()))
+}
+
+/// Refresh the cached signing keys from the user store. Keys are also
+/// reloaded when a token signed by an unknown key shows up.
+pub async fn load_signing_keys(auth: &Auth) -> Result<(), AuthError> {
+    let config = jwt_config(auth)?;
+    config.set_keys(auth.users.get_signing_keys(unix_now()).await?)
+}
+
+/// Store a key and sign new tokens with it, retiring the key it replaces.
+/// Adding a key that is already stored does nothing.
+pub async fn add_signing_key(auth: &Auth, key: SigningKey) -> Result<(), AuthError> {
+    let config = jwt_config(auth)?;
+    load_signing_keys(auth).await?;
+    if config.has_key(&key.kid) {
+        return Ok(());
+    }
+    let old = config.active_key();
+    auth.users.add_signing_key(key).await?;
+    if let Some(old) = old {
+        retire_signing_key(auth, old.kid).await?;
+    }
+    load_signing_keys(auth).await
+}
+
+/// Stop a key from signing new tokens. It is still accepted, and listed in
+/// the JWKS, until every token it signed has expired.
+pub async fn retire_signing_key(auth: &Auth, kid: String) -> Result<(), AuthError> {
+    jwt_config(auth)?;
+    let now = unix_now();
+    let expires_at = now + auth.access_token_expire_time() as u64;
+    auth.users.retire_signing_key(kid, now, expires_at).await?;
+    load_signing_keys(auth).await
+}
+
+/// Generate a new signing key and retire the ones it replaces, returning
+/// the new key id
+pub async fn rotate_signing_key(auth: &Auth) -> Result<String, AuthError> {
+    let key = SigningKey::generate(jwt_config(auth)?.algorithm()).await?;
+    let kid = key.kid.clone();
+    add_signing_key(auth, key).await?;
+    Ok(kid)
+}
+
+/// Rotate the signing key if it is older than `max_age` seconds, uses
+/// another algorithm than the config, or if there is no key yet. Meant to be
+/// called on a timer.
+pub async fn rotate_signing_key_if_older(
+    auth: &Auth,
+    max_age: u64,
+) -> Result<Option<String>, AuthError> {
+    let config = jwt_config(auth)?;
+    load_signing_keys(auth).await?;
+    match config.active_key() {
+        Some(key)
+            if key.algorithm == config.algorithm()
+                && key.created_at.saturating_add(max_age) > unix_now() =>
+        {
+            Ok(None)
+        }
+        _ => rotate_signing_key(auth).await.map(Some),
     }
 }
 
-pub async fn verify_token(auth: &mut Auth, token: String) -> Result<String, AuthError> {
-    match auth.redis.get::<_, Option<i32>>(token.clone()) {
-        Ok(Some(id)) => {
-            match auth.token_expire_time {
-                Some(x) => {
-                    let _ = auth
-                        .redis
-                        .expire(token.clone(), x)
-                        .or_else(|x| Err(AuthError::RedisError(x)))?;
-                }
-                None => {}
+/// Public keys for verifying access tokens, HS256 keys are never included.
+/// Empty if JWT mode is off.
+pub async fn jwks(auth: &Auth) -> Result<Jwks, AuthError> {
+    let Some(config) = &auth.jwt else {
+        return Ok(Jwks { keys: Vec::new() });
+    };
+    load_signing_keys(auth).await?;
+    Ok(config.jwks())
+}
+
+/// Every session of the token's user that has not ended, most recently used
+/// first
+pub async fn list_sessions(auth: &Auth, token: String) -> Result<Vec<Session>, AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let mut sessions = auth.tokens.get_user_sessions(id).await?;
+    sessions.sort_by_key(|x| Reverse(x.last_used_at));
+    Ok(sessions)
+}
+
+/// End one of the sessions of the token's user
+pub async fn revoke_session(auth: &Auth, token: String, session: String) -> Result<(), AuthError> {
+    let id = get_id_from_token(auth, token).await?;
+    let sessions = auth.tokens.get_user_sessions(id).await?;
+    if !sessions.iter().any(|x| x.id == session) {
+        return Err(AuthError::SessionDoesNotExist);
+    }
+    end_session(auth, session).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "user@example.com";
+    const PASSWORD: &str = "password";
+
+    /// Auth objects to run a test against, keeping users in memory. Tokens
+    /// are kept in memory, and in redis as well when `TEST_REDIS_URL` is set.
+    async fn auths() -> Vec<Auth> {
+        let mut auths = vec![init_memory_auth()];
+        if let Ok(url) = std::env::var("TEST_REDIS_URL") {
+            let tokens = RedisTokenStore::new(url)
+                .await
+                .unwrap()
+                .prefix(format!("test:{}:", random_token()));
+            auths.push(Auth::new(MemoryUserStore::new(), tokens));
+        }
+        auths
+    }
+
+    /// Keeps the bodies of the emails it is asked to send, or fails to
+    /// send them
+    #[derive(Clone, Default)]
+    struct TestMailer {
+        sent: Arc<std::sync::Mutex<Vec<String>>>,
+        failing: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Mailer for TestMailer {
+        async fn send(&self, _: String, _: String, body: String) -> Result<(), AuthError> {
+            if self.failing {
+                return Err(AuthError::MailError("could not send".to_string()));
+            }
+            self.sent.lock().unwrap().push(body);
+            Ok(())
+        }
+    }
+
+    async fn login_tokens(auth: &Auth) -> LoginTokens {
+        let client = ClientInfo::default();
+        match login(auth, EMAIL.to_string(), PASSWORD.to_string(), client).await {
+            Ok(LoginResult::Tokens(x)) => x,
+            Ok(LoginResult::MfaRequired { .. }) => panic!("mfa is not enabled"),
+            Err(x) => panic!("could not log in: {}", x),
+        }
+    }
+
+    async fn is_valid(auth: &Auth, token: String) -> bool {
+        verify_token(auth, token).await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn logs_in_and_out() {
+        for auth in auths().await {
+            create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+                .await
+                .unwrap();
+            let result = create_user(&auth, EMAIL.to_string(), PASSWORD.to_string()).await;
+            assert!(matches!(result, Err(AuthError::UserAlreadyExists)));
+            let client = ClientInfo::default();
+            let result = login(&auth, EMAIL.to_string(), "wrong".to_string(), client).await;
+            assert!(matches!(
+                result,
+                Err(AuthError::IncorrectUsernameOrPassword)
+            ));
+            let client = ClientInfo::default();
+            let result = login(
+                &auth,
+                "nobody@example.com".to_string(),
+                "x".to_string(),
+                client,
+            );
+            assert!(matches!(result.await, Err(AuthError::UserDoesNotExist)));
+
+            let token = login_tokens(&auth).await.token;
+            let info = verify_token(&auth, token.clone()).await.unwrap().unwrap();
+            assert_eq!(info.email, EMAIL);
+            assert!(info.session.is_some());
+            assert!(!is_valid(&auth, random_token()).await);
+
+            logout(&auth, token.clone()).await.unwrap();
+            assert!(!is_valid(&auth, token.clone()).await);
+            assert!(matches!(
+                logout(&auth, token).await,
+                Err(AuthError::TokenDoesNotExist)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_work_once() {
+        for auth in auths().await {
+            let auth = auth.refresh_token_expire_time(Some(60));
+            create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+                .await
+                .unwrap();
+            let first = login_tokens(&auth).await;
+            let first_refresh = first.refresh_token.unwrap();
+            let second = refresh_token(&auth, first_refresh.clone()).await.unwrap();
+            assert!(is_valid(&auth, second.token.clone()).await);
+            // using one again ends the session
+            assert!(matches!(
+                refresh_token(&auth, first_refresh).await,
+                Err(AuthError::InvalidToken)
+            ));
+            assert!(!is_valid(&auth, second.token).await);
+            assert!(matches!(
+                refresh_token(&auth, second.refresh_token.unwrap()).await,
+                Err(AuthError::TokenDoesNotExist)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_logins_after_failures() {
+        for auth in auths().await {
+            create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+                .await
+                .unwrap();
+            for _ in 0..auth.login_limits.max_account_failures {
+                let client = ClientInfo::default();
+                let result = login(&auth, EMAIL.to_string(), "wrong".to_string(), client).await;
+                assert!(matches!(
+                    result,
+                    Err(AuthError::IncorrectUsernameOrPassword)
+                ));
             }
-            Ok(get_user_by_id(auth, id).await?.1)
+            let client = ClientInfo::default();
+            let result = login(&auth, EMAIL.to_string(), PASSWORD.to_string(), client).await;
+            assert!(matches!(result, Err(AuthError::AccountLocked(_))));
+        }
+    }
+
+    /// Other kinds of keys the token store keeps must not pass for tokens,
+    /// whatever their name
+    #[tokio::test]
+    async fn tokens_can_not_name_other_keys() {
+        for auth in auths().await {
+            let auth = auth.refresh_token_expire_time(Some(60));
+            create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+                .await
+                .unwrap();
+            let tokens = login_tokens(&auth).await;
+            let refresh = format!("refresh:{}", tokens.refresh_token.unwrap());
+            assert!(!is_valid(&auth, refresh.clone()).await);
+            assert!(refresh_token(&auth, refresh).await.is_err());
+
+            let enrollment = enroll_totp(&auth, tokens.token.clone()).await.unwrap();
+            let code = totp::generate(&enrollment.secret, unix_now());
+            confirm_totp(&auth, tokens.token, code).await.unwrap();
+            let client = ClientInfo::default();
+            let challenge = match login(&auth, EMAIL.to_string(), PASSWORD.to_string(), client)
+                .await
+                .unwrap()
+            {
+                LoginResult::MfaRequired { challenge } => challenge,
+                LoginResult::Tokens(_) => panic!("mfa is enabled"),
+            };
+            let challenge = format!("mfa:{}", challenge);
+            assert!(!is_valid(&auth, challenge.clone()).await);
+            let result = update_user(&auth, challenge, None, Some("new".to_string()), false);
+            assert!(result.await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_sessions_are_not_tokens() {
+        for auth in auths().await {
+            let auth = auth.jwt(JwtConfig::new(jwt::JwtAlgorithm::HS256));
+            add_signing_key(&auth, SigningKey::hs256("secret".to_string()).unwrap())
+                .await
+                .unwrap();
+            create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+                .await
+                .unwrap();
+            let token = login_tokens(&auth).await.token;
+            let session = verify_token(&auth, token.clone())
+                .await
+                .unwrap()
+                .unwrap()
+                .session
+                .unwrap();
+            logout(&auth, token.clone()).await.unwrap();
+            assert!(!is_valid(&auth, token).await);
+            assert!(!is_valid(&auth, format!("denied:{}", session)).await);
         }
-        Ok(None) => Ok(String::from("")),
-        Err(x) => Err(AuthError::RedisError(x)),
+    }
+
+    #[tokio::test]
+    async fn removes_users_whose_email_can_not_be_sent() {
+        let mailer = TestMailer {
+            failing: true,
+            ..TestMailer::default()
+        };
+        let auth = init_memory_auth().mailer(mailer);
+        let result = create_user(&auth, EMAIL.to_string(), PASSWORD.to_string()).await;
+        assert!(matches!(result, Err(AuthError::MailError(_))));
+        let client = ClientInfo::default();
+        let result = login(&auth, EMAIL.to_string(), PASSWORD.to_string(), client).await;
+        assert!(matches!(result, Err(AuthError::UserDoesNotExist)));
+    }
+
+    #[tokio::test]
+    async fn resends_verification_emails() {
+        let mailer = TestMailer::default();
+        let auth = init_memory_auth()
+            .mailer(mailer.clone())
+            .require_verified_email(true);
+        create_user(&auth, EMAIL.to_string(), PASSWORD.to_string())
+            .await
+            .unwrap();
+        resend_verification_email(&auth, EMAIL.to_string())
+            .await
+            .unwrap();
+        // unknown emails look the same
+        resend_verification_email(&auth, "nobody@example.com".to_string())
+            .await
+            .unwrap();
+        let sent = mailer.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        let token = |body: &str| body.rsplit(' ').next().unwrap().to_string();
+        // only the newest token works
+        assert!(verify_email(&auth, token(&sent[0])).await.is_err());
+        verify_email(&auth, token(&sent[1])).await.unwrap();
+        login_tokens(&auth).await;
+        resend_verification_email(&auth, EMAIL.to_string())
+            .await
+            .unwrap();
+        assert_eq!(mailer.sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn resets_passwords_once() {
+        let mailer = TestMailer::default();
+        let auth = init_memory_auth().mailer(mailer.clone());
+        create_user(&auth, EMAIL.to_string(), "old password".to_string())
+            .await
+            .unwrap();
+        request_password_reset(&auth, EMAIL.to_string())
+            .await
+            .unwrap();
+        let body = mailer.sent.lock().unwrap().last().unwrap().clone();
+        let token = body.rsplit(' ').next().unwrap().to_string();
+        complete_password_reset(&auth, token.clone(), PASSWORD.to_string())
+            .await
+            .unwrap();
+        login_tokens(&auth).await;
+        assert!(matches!(
+            complete_password_reset(&auth, token, "other".to_string()).await,
+            Err(AuthError::InvalidToken)
+        ));
+        login_tokens(&auth).await;
+    }
+
+    #[test]
+    #[should_panic(expected = "per_second")]
+    fn rejects_rate_limits_that_never_refill() {
+        init_memory_auth().rate_limit(RateLimit {
+            burst: 1,
+            per_second: 0.0,
+            by: RateLimitBy::Ip,
+        });
     }
 }
@@ -1,31 +1,151 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub enum AuthError {
     // User Errors
     UserAlreadyExists,
     UserDoesNotExist,
     IncorrectUsernameOrPassword,
+    PermissionDenied,
+    RoleDoesNotExist,
+    EmailNotVerified,
+    // Rate Limit Errors, carrying how many seconds to wait before trying again
+    AccountLocked(u64),
+    TooManyAttempts(u64),
+    TooManyRequests(u64),
+    // Two-factor Errors
+    IncorrectMfaCode,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    PasskeyDoesNotExist,
+    InvalidPasskey(String),
     // Token Errors
     InvalidToken,
     TokenDoesNotExist,
+    SessionDoesNotExist,
     UnableToAquireTokenListLock,
+    // Password Hashing Error
+    PasswordHashError(String),
+    // Access token signing error
+    JwtError(String),
+    SigningKeyDoesNotExist,
+    // Passkey configuration or key handling error
+    WebauthnError(String),
+    // Mail delivery error
+    MailError(String),
     // Database Error
     PostgresError(sqlx::Error),
     RedisError(redis::RedisError),
+    SqliteError(sqlx::Error),
 }
 
 impl AuthError {
-    pub fn to_error_message(self) -> &'static str {
+    pub fn to_error_message(&self) -> &'static str {
         const SERVER_SIDE_ERROR_MESSAGE: &str =
             "An error occured while processing your request. Please try again later.";
         match self {
             AuthError::UserAlreadyExists => "User already exists",
             AuthError::UserDoesNotExist => "User does not exist",
             AuthError::IncorrectUsernameOrPassword => "Incorrect username or password",
+            AuthError::PermissionDenied => "You do not have permission to do that",
+            AuthError::RoleDoesNotExist => "Role does not exist",
+            AuthError::EmailNotVerified => "Email address is not verified",
+            AuthError::AccountLocked(_) => {
+                "Account is locked after too many failed logins. Please try again later."
+            }
+            AuthError::TooManyAttempts(_) => "Too many failed logins. Please try again later.",
+            AuthError::TooManyRequests(_) => "Too many requests. Please try again later.",
+            AuthError::IncorrectMfaCode => "Incorrect verification code",
+            AuthError::TotpAlreadyEnabled => "Two-factor authentication is already enabled",
+            AuthError::TotpNotEnabled => "Two-factor authentication is not set up",
+            AuthError::PasskeyDoesNotExist => "Passkey does not exist",
+            AuthError::InvalidPasskey(_) => "Passkey could not be verified",
             AuthError::InvalidToken => "Invalid token",
             AuthError::TokenDoesNotExist => "Token does not exist",
+            AuthError::SessionDoesNotExist => "Session does not exist",
             AuthError::UnableToAquireTokenListLock => SERVER_SIDE_ERROR_MESSAGE,
+            AuthError::PasswordHashError(_) => SERVER_SIDE_ERROR_MESSAGE,
+            AuthError::JwtError(_) => SERVER_SIDE_ERROR_MESSAGE,
+            AuthError::SigningKeyDoesNotExist => "Signing key does not exist",
+            AuthError::WebauthnError(_) => SERVER_SIDE_ERROR_MESSAGE,
+            AuthError::MailError(_) => SERVER_SIDE_ERROR_MESSAGE,
             AuthError::PostgresError(_) => SERVER_SIDE_ERROR_MESSAGE,
             AuthError::RedisError(_) => SERVER_SIDE_ERROR_MESSAGE,
+            AuthError::SqliteError(_) => SERVER_SIDE_ERROR_MESSAGE,
+        }
+    }
+
+    /// Machine readable name of the error, safe to show to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::UserAlreadyExists => "user_already_exists",
+            AuthError::UserDoesNotExist => "user_does_not_exist",
+            AuthError::IncorrectUsernameOrPassword => "incorrect_username_or_password",
+            AuthError::PermissionDenied => "permission_denied",
+            AuthError::RoleDoesNotExist => "role_does_not_exist",
+            AuthError::EmailNotVerified => "email_not_verified",
+            AuthError::AccountLocked(_) => "account_locked",
+            AuthError::TooManyAttempts(_) => "too_many_attempts",
+            AuthError::TooManyRequests(_) => "too_many_requests",
+            AuthError::IncorrectMfaCode => "incorrect_mfa_code",
+            AuthError::TotpAlreadyEnabled => "totp_already_enabled",
+            AuthError::TotpNotEnabled => "totp_not_enabled",
+            AuthError::PasskeyDoesNotExist => "passkey_does_not_exist",
+            AuthError::InvalidPasskey(_) => "invalid_passkey",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::TokenDoesNotExist => "token_does_not_exist",
+            AuthError::SessionDoesNotExist => "session_does_not_exist",
+            AuthError::SigningKeyDoesNotExist => "signing_key_does_not_exist",
+            AuthError::UnableToAquireTokenListLock
+            | AuthError::PasswordHashError(_)
+            | AuthError::JwtError(_)
+            | AuthError::WebauthnError(_)
+            | AuthError::MailError(_) => "internal_error",
+            AuthError::PostgresError(_) | AuthError::RedisError(_) | AuthError::SqliteError(_) => {
+                "storage_unavailable"
+            }
+        }
+    }
+
+    /// Seconds to wait before trying again, for errors that go away on
+    /// their own
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            AuthError::AccountLocked(x)
+            | AuthError::TooManyAttempts(x)
+            | AuthError::TooManyRequests(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// Unlike `to_error_message`, this includes the underlying error and is
+/// meant for logs rather than clients
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::AccountLocked(x) => write!(f, "account locked for {} seconds", x),
+            AuthError::TooManyAttempts(x) => write!(f, "logins refused for {} seconds", x),
+            AuthError::TooManyRequests(x) => write!(f, "requests refused for {} seconds", x),
+            AuthError::PasswordHashError(x) => write!(f, "password hash error: {}", x),
+            AuthError::JwtError(x) => write!(f, "jwt error: {}", x),
+            AuthError::InvalidPasskey(x) => write!(f, "invalid passkey: {}", x),
+            AuthError::WebauthnError(x) => write!(f, "webauthn error: {}", x),
+            AuthError::MailError(x) => write!(f, "mail error: {}", x),
+            AuthError::PostgresError(x) => write!(f, "postgres error: {}", x),
+            AuthError::RedisError(x) => write!(f, "redis error: {}", x),
+            AuthError::SqliteError(x) => write!(f, "sqlite error: {}", x),
+            _ => f.write_str(self.to_error_message()),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::PostgresError(x) | AuthError::SqliteError(x) => Some(x),
+            AuthError::RedisError(x) => Some(x),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,444 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use openssl::{
+    pkey::{Id, PKey},
+    rsa::Rsa,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use crate::{
+    util::{random_token, unix_now},
+    AuthError,
+};
+
+/// How long access tokens last when no token expire time is set, since
+/// they can not be refreshed like opaque tokens
+pub const DEFAULT_JWT_EXPIRE_TIME: usize = 15 * 60;
+
+/// Seconds between reloading keys for tokens naming a key that is not
+/// cached, as anyone can make up a `kid`
+const UNKNOWN_KEY_RELOAD_INTERVAL: u64 = 5;
+
+/// Algorithm access tokens are signed with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    #[default]
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+impl JwtAlgorithm {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+            JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+impl fmt::Display for JwtAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JwtAlgorithm::HS256 => "HS256",
+            JwtAlgorithm::RS256 => "RS256",
+            JwtAlgorithm::EdDSA => "EdDSA",
+        })
+    }
+}
+
+impl FromStr for JwtAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HS256" => Ok(JwtAlgorithm::HS256),
+            "RS256" => Ok(JwtAlgorithm::RS256),
+            "EdDSA" => Ok(JwtAlgorithm::EdDSA),
+            _ => Err(format!("unknown jwt algorithm {}", s)),
+        }
+    }
+}
+
+/// A key access tokens are signed with, as kept by a
+/// [`UserStore`](crate::UserStore). Times are in unix seconds.
+#[derive(Clone)]
+pub struct SigningKey {
+    /// Sent in the `kid` header of tokens so the right key can be found
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    /// PEM encoded private key, or the secret for HS256
+    pub private_key: String,
+    /// PEM encoded public key, empty for HS256
+    pub public_key: String,
+    pub created_at: u64,
+    /// When the key stopped signing new tokens
+    pub retired_at: Option<u64>,
+    /// When tokens signed by the key stop being accepted
+    pub expires_at: Option<u64>,
+}
+
+impl SigningKey {
+    pub fn hs256(secret: String) -> Result<Self, AuthError> {
+        Self::new(JwtAlgorithm::HS256, secret, String::new())
+    }
+
+    /// From PEM encoded RSA keys
+    pub fn rs256(private_key: String, public_key: String) -> Result<Self, AuthError> {
+        Self::new(JwtAlgorithm::RS256, private_key, public_key)
+    }
+
+    /// From PEM encoded Ed25519 keys
+    pub fn eddsa(private_key: String, public_key: String) -> Result<Self, AuthError> {
+        Self::new(JwtAlgorithm::EdDSA, private_key, public_key)
+    }
+
+    /// Make a new random key, RSA keys are 2048 bits
+    pub async fn generate(algorithm: JwtAlgorithm) -> Result<Self, AuthError> {
+        if algorithm == JwtAlgorithm::HS256 {
+            return Self::hs256(random_token());
+        }
+        // generating RSA keys takes a while, so keep it off the workers
+        let (private_key, public_key) = tokio::task::spawn_blocking(move || {
+            let key = match algorithm {
+                JwtAlgorithm::RS256 => Rsa::generate(2048).and_then(PKey::from_rsa),
+                _ => PKey::generate_ed25519(),
+            }?;
+            Ok::<_, openssl::error::ErrorStack>((
+                key.private_key_to_pem_pkcs8()?,
+                key.public_key_to_pem()?,
+            ))
+        })
+        .await
+        .map_err(|x| AuthError::JwtError(x.to_string()))?
+        .map_err(|x| AuthError::JwtError(x.to_string()))?;
+        Self::new(
+            algorithm,
+            String::from_utf8_lossy(&private_key).into_owned(),
+            String::from_utf8_lossy(&public_key).into_owned(),
+        )
+    }
+
+    /// The key id is derived from the key, so adding the same key twice is a no-op
+    fn new(
+        algorithm: JwtAlgorithm,
+        private_key: String,
+        public_key: String,
+    ) -> Result<Self, AuthError> {
+        let material = match algorithm {
+            JwtAlgorithm::HS256 => &private_key,
+            _ => &public_key,
+        };
+        let kid = Sha256::digest(material.as_bytes())
+            .iter()
+            .take(8)
+            .map(|x| format!("{:02x}", x))
+            .collect();
+        let key = SigningKey {
+            kid,
+            algorithm,
+            private_key,
+            public_key,
+            created_at: unix_now(),
+            retired_at: None,
+            expires_at: None,
+        };
+        // make sure the keys parse before they get stored
+        LoadedKey::new(key.clone())?;
+        Ok(key)
+    }
+
+    fn is_active(&self, now: u64) -> bool {
+        self.retired_at.is_none_or(|x| x > now)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
+
+/// A public key in the format of RFC 7517
+#[derive(Clone, Debug, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+}
+
+/// The body of `/.well-known/jwks.json`
+#[derive(Clone, Debug, Serialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Clone)]
+struct LoadedKey {
+    key: SigningKey,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    /// `None` for HS256, the secret must not be published
+    jwk: Option<Jwk>,
+}
+
+impl LoadedKey {
+    fn new(key: SigningKey) -> Result<Self, AuthError> {
+        let private_key = key.private_key.as_bytes();
+        let public_key = key.public_key.as_bytes();
+        let (encoding_key, decoding_key) = match key.algorithm {
+            JwtAlgorithm::HS256 => (
+                EncodingKey::from_secret(private_key),
+                DecodingKey::from_secret(private_key),
+            ),
+            JwtAlgorithm::RS256 => (
+                EncodingKey::from_rsa_pem(private_key).map_err(jwt_error)?,
+                DecodingKey::from_rsa_pem(public_key).map_err(jwt_error)?,
+            ),
+            JwtAlgorithm::EdDSA => (
+                EncodingKey::from_ed_pem(private_key).map_err(jwt_error)?,
+                DecodingKey::from_ed_pem(public_key).map_err(jwt_error)?,
+            ),
+        };
+        let jwk = match key.algorithm {
+            JwtAlgorithm::HS256 => None,
+            _ => Some(public_jwk(&key)?),
+        };
+        Ok(LoadedKey {
+            key,
+            encoding_key,
+            decoding_key,
+            jwk,
+        })
+    }
+}
+
+fn public_jwk(key: &SigningKey) -> Result<Jwk, AuthError> {
+    let openssl_error = |x: openssl::error::ErrorStack| AuthError::JwtError(x.to_string());
+    let public_key = PKey::public_key_from_pem(key.public_key.as_bytes()).map_err(openssl_error)?;
+    let mut jwk = Jwk {
+        kty: "RSA",
+        kid: key.kid.clone(),
+        alg: key.algorithm.to_string(),
+        use_: "sig",
+        n: None,
+        e: None,
+        crv: None,
+        x: None,
+    };
+    if public_key.id() == Id::ED25519 {
+        jwk.kty = "OKP";
+        jwk.crv = Some("Ed25519");
+        jwk.x = Some(URL_SAFE_NO_PAD.encode(public_key.raw_public_key().map_err(openssl_error)?));
+    } else {
+        let rsa = public_key.rsa().map_err(openssl_error)?;
+        jwk.n = Some(URL_SAFE_NO_PAD.encode(rsa.n().to_vec()));
+        jwk.e = Some(URL_SAFE_NO_PAD.encode(rsa.e().to_vec()));
+    }
+    Ok(jwk)
+}
+
+/// Signing keys for access tokens, see [`Auth::jwt`](crate::Auth::jwt).
+/// Keys are kept in the user store and cached here, clones share the cache.
+#[derive(Clone)]
+pub struct JwtConfig {
+    algorithm: JwtAlgorithm,
+    keys: Arc<RwLock<Vec<LoadedKey>>>,
+    /// When keys were last reloaded for an unknown key, in unix seconds
+    last_reload: Arc<AtomicU64>,
+}
+
+impl JwtConfig {
+    /// `algorithm` is used for keys made when rotating, existing keys of
+    /// other algorithms keep working
+    pub fn new(algorithm: JwtAlgorithm) -> Self {
+        JwtConfig {
+            algorithm,
+            keys: Arc::default(),
+            last_reload: Arc::default(),
+        }
+    }
+
+    pub fn algorithm(&self) -> JwtAlgorithm {
+        self.algorithm
+    }
+
+    /// Cached keys that have not expired, newest first
+    fn keys(&self) -> Vec<LoadedKey> {
+        let now = unix_now();
+        let keys = self.keys.read().unwrap_or_else(PoisonError::into_inner);
+        keys.iter()
+            .filter(|x| !x.key.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    fn active(&self) -> Option<LoadedKey> {
+        let now = unix_now();
+        self.keys().into_iter().find(|x| x.key.is_active(now))
+    }
+
+    /// Replace the cached keys, newest first
+    pub(crate) fn set_keys(&self, keys: Vec<SigningKey>) -> Result<(), AuthError> {
+        let mut keys = keys
+            .into_iter()
+            .map(LoadedKey::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.sort_by_key(|x| Reverse(x.key.created_at));
+        *self.keys.write().unwrap_or_else(PoisonError::into_inner) = keys;
+        Ok(())
+    }
+
+    /// The newest key that has not been retired signs new tokens
+    pub(crate) fn active_key(&self) -> Option<SigningKey> {
+        self.active().map(|x| x.key)
+    }
+
+    pub(crate) fn has_key(&self, kid: &str) -> bool {
+        self.keys().iter().any(|x| x.key.kid == kid)
+    }
+
+    /// Whether to reload the keys for a token naming a key that is not
+    /// cached, which happens when another instance rotated keys. Keys are
+    /// reloaded at most every few seconds however many such tokens come in.
+    pub(crate) fn should_reload(&self, token: &str) -> bool {
+        let unknown = match jsonwebtoken::decode_header(token) {
+            Ok(Header { kid: Some(kid), .. }) => {
+                let keys = self.keys.read().unwrap_or_else(PoisonError::into_inner);
+                !keys.iter().any(|x| x.key.kid == kid)
+            }
+            _ => false,
+        };
+        let now = unix_now();
+        let last = self.last_reload.load(Ordering::Relaxed);
+        // only one of the requests that find the interval over reloads
+        unknown
+            && now >= last + UNKNOWN_KEY_RELOAD_INTERVAL
+            && self
+                .last_reload
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    pub(crate) fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: self.keys().into_iter().filter_map(|x| x.jwk).collect(),
+        }
+    }
+}
+
+/// Claims of an access token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// Id of the user, as a string like the JWT spec asks for
+    pub sub: String,
+    pub email: String,
+    pub exp: u64,
+    pub iat: u64,
+    /// Unique id of the token, used to deny it after logging out
+    pub jti: String,
+    /// Id of the session the token was issued in, denied when the session
+    /// ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Result<i32, AuthError> {
+        self.sub.parse().map_err(|_| AuthError::InvalidToken)
+    }
+}
+
+fn jwt_error(err: jsonwebtoken::errors::Error) -> AuthError {
+    AuthError::JwtError(err.to_string())
+}
+
+/// Access tokens are the only tokens with dots in them, opaque ones are alphanumeric
+pub(crate) fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+pub(crate) fn issue(
+    config: &JwtConfig,
+    id: i32,
+    email: String,
+    session: Option<String>,
+    expire: usize,
+) -> Result<String, AuthError> {
+    let Some(key) = config.active() else {
+        return Err(AuthError::JwtError("no active signing key".to_string()));
+    };
+    let now = unix_now();
+    let claims = Claims {
+        sub: id.to_string(),
+        email,
+        exp: now + expire as u64,
+        iat: now,
+        jti: random_token(),
+        sid: session,
+    };
+    let mut header = Header::new(key.key.algorithm.algorithm());
+    header.kid = Some(key.key.kid);
+    jsonwebtoken::encode(&header, &claims, &key.encoding_key).map_err(jwt_error)
+}
+
+/// Check the signature and expiry of a token, does not check the denylist.
+/// Tokens without a `kid` are tried against every key.
+pub(crate) fn decode(config: &JwtConfig, token: &str) -> Result<Claims, AuthError> {
+    let kid = match jsonwebtoken::decode_header(token) {
+        Ok(header) => header.kid,
+        Err(_) => return Err(AuthError::InvalidToken),
+    };
+    for key in config.keys() {
+        if kid.as_ref().is_some_and(|x| *x != key.key.kid) {
+            continue;
+        }
+        let mut validation = Validation::new(key.key.algorithm.algorithm());
+        validation.leeway = 0;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Ok(data) = jsonwebtoken::decode::<Claims>(token, &key.decoding_key, &validation) {
+            return Ok(data.claims);
+        }
+    }
+    Err(AuthError::InvalidToken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloads_for_unknown_keys_at_most_every_few_seconds() {
+        let config = JwtConfig::new(JwtAlgorithm::HS256);
+        let token = |kid: &str| {
+            let header = Header {
+                kid: Some(kid.to_string()),
+                ..Header::default()
+            };
+            let key = EncodingKey::from_secret(b"secret");
+            jsonwebtoken::encode(&header, &serde_json::json!({ "sub": "1" }), &key).unwrap()
+        };
+        assert!(config.should_reload(&token("first")));
+        assert!(!config.should_reload(&token("second")));
+        assert!(!config.should_reload(&token("first")));
+    }
+}
@@ -1,4 +1,21 @@
-pub use self::{auth::*, error::*};
+pub use self::{
+    auth::*,
+    error::*,
+    jwt::{Claims, Jwk, Jwks, JwtAlgorithm, JwtConfig, SigningKey, DEFAULT_JWT_EXPIRE_TIME},
+    mail::{FileMailer, Mailer, StdoutMailer},
+    password::HashAlgorithm,
+    store::*,
+    webauthn::{
+        AssertionResponse, AttestationResponse, LoginCredential, RegistrationCredential,
+        WebauthnConfig,
+    },
+};
 pub mod auth;
 mod error;
+mod jwt;
+mod mail;
+mod password;
+pub mod store;
+mod totp;
 mod util;
+mod webauthn;
@@ -0,0 +1,57 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::AuthError;
+
+/// Delivers the emails sent to users, such as the ones verifying their
+/// address. Implement this to send them through a real mail service.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: String, subject: String, body: String) -> Result<(), AuthError>;
+}
+
+fn format_mail(to: &str, subject: &str, body: &str) -> String {
+    format!("To: {}\nSubject: {}\n\n{}\n\n", to, subject, body)
+}
+
+/// Writes emails to the process's stdout instead of sending them, for local
+/// testing. It does not go through any logger, so do not use it in services
+/// whose stdout is read by something else.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutMailer;
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, to: String, subject: String, body: String) -> Result<(), AuthError> {
+        print!("{}", format_mail(&to, &subject, &body));
+        Ok(())
+    }
+}
+
+/// Appends emails to a file instead of sending them, for local testing
+#[derive(Clone, Debug)]
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileMailer { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, to: String, subject: String, body: String) -> Result<(), AuthError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|x| AuthError::MailError(x.to_string()))?;
+        file.write_all(format_mail(&to, &subject, &body).as_bytes())
+            .await
+            .map_err(|x| AuthError::MailError(x.to_string()))
+    }
+}
@@ -0,0 +1,114 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+use super::error::AuthError;
+
+/// Algorithm used to hash new passwords. Existing hashes are verified with
+/// whichever algorithm produced them, so this can be changed at any time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+    Scrypt,
+    Pbkdf2,
+}
+
+impl HashAlgorithm {
+    /// Hash a password, returning a PHC string (or a modular crypt string for bcrypt)
+    pub(crate) async fn hash_password(&self, password: String) -> Result<String, AuthError> {
+        let algorithm = *self;
+        blocking(move || algorithm.hash(&password)).await
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AuthError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = match self {
+            HashAlgorithm::Argon2id => Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|x| x.to_string()),
+            HashAlgorithm::Scrypt => Scrypt
+                .hash_password(password.as_bytes(), &salt)
+                .map(|x| x.to_string()),
+            HashAlgorithm::Pbkdf2 => Pbkdf2
+                .hash_password(password.as_bytes(), &salt)
+                .map(|x| x.to_string()),
+            HashAlgorithm::Bcrypt => {
+                return bcrypt::hash(password, bcrypt::DEFAULT_COST)
+                    .map_err(|x| AuthError::PasswordHashError(x.to_string()))
+            }
+        };
+        hash.map_err(|x| AuthError::PasswordHashError(x.to_string()))
+    }
+
+    /// Whether a stored hash should be replaced with one made by this algorithm
+    pub(crate) fn needs_rehash(&self, passwordhash: &str) -> bool {
+        let prefix = match self {
+            HashAlgorithm::Argon2id => "$argon2id$",
+            HashAlgorithm::Bcrypt => "$2",
+            HashAlgorithm::Scrypt => "$scrypt$",
+            HashAlgorithm::Pbkdf2 => "$pbkdf2",
+        };
+        !passwordhash.starts_with(prefix)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "argon2id" | "argon2" => Ok(HashAlgorithm::Argon2id),
+            "bcrypt" => Ok(HashAlgorithm::Bcrypt),
+            "scrypt" => Ok(HashAlgorithm::Scrypt),
+            "pbkdf2" => Ok(HashAlgorithm::Pbkdf2),
+            _ => Err(format!("unknown hash algorithm {}", s)),
+        }
+    }
+}
+
+/// Check a password against a stored hash. Rows created before PHC hashes
+/// were introduced hold an uppercase hex SHA-256 of `password + salt`.
+pub(crate) async fn verify_password(
+    password: String,
+    passwordhash: String,
+    salt: String,
+) -> Result<bool, AuthError> {
+    blocking(move || verify(&password, &passwordhash, &salt)).await
+}
+
+/// Hashing is slow on purpose, so it runs on the blocking thread pool instead
+/// of stalling every other request handled by the same worker
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AuthError> + Send + 'static,
+) -> Result<T, AuthError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|x| AuthError::PasswordHashError(x.to_string()))?
+}
+
+fn verify(password: &str, passwordhash: &str, salt: &str) -> Result<bool, AuthError> {
+    if !passwordhash.starts_with('$') {
+        return Ok(legacy_hash(password, salt) == passwordhash);
+    }
+    if passwordhash.starts_with("$2") {
+        return bcrypt::verify(password, passwordhash)
+            .map_err(|x| AuthError::PasswordHashError(x.to_string()));
+    }
+    let parsed =
+        PasswordHash::new(passwordhash).map_err(|x| AuthError::PasswordHashError(x.to_string()))?;
+    let verifiers: [&dyn PasswordVerifier; 3] = [&Argon2::default(), &Scrypt, &Pbkdf2];
+    Ok(parsed.verify_password(&verifiers, password).is_ok())
+}
+
+fn legacy_hash(password: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(password.to_owned() + salt);
+    format!("{:X}", hasher.finalize())
+}
@@ -0,0 +1,766 @@
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::{
+    EmailTokenKind, LoginFailures, MfaChallenge, Passkey, RefreshToken, Session, Token, TokenStore,
+    Totp, User, UserStore, WebauthnChallenge,
+};
+use crate::{util::unix_now, AuthError, SigningKey};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // the maps are never left half updated, so a poisoned lock is still usable
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct Users {
+    next_id: i32,
+    users: HashMap<i32, User>,
+    /// Permissions of each role
+    roles: HashMap<String, BTreeSet<String>>,
+    /// Roles of each user
+    user_roles: HashMap<i32, BTreeSet<String>>,
+    signing_keys: HashMap<String, SigningKey>,
+    /// User id, kind and expire time of each email token hash
+    email_tokens: HashMap<String, (i32, EmailTokenKind, u64)>,
+    totp: HashMap<i32, Totp>,
+    /// Hashes of the recovery codes of each user
+    recovery_codes: HashMap<i32, BTreeSet<String>>,
+    passkeys: HashMap<String, Passkey>,
+}
+
+impl Users {
+    fn find(&self, email: &str) -> Option<&User> {
+        self.users.values().find(|user| user.email == email)
+    }
+}
+
+/// Keeps users in memory, for tests and single process embedding
+#[derive(Clone, Default)]
+pub struct MemoryUserStore {
+    users: Arc<Mutex<Users>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn create_user(&self, email: String, passwordhash: String) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        if users.find(&email).is_some() {
+            return Err(AuthError::UserAlreadyExists);
+        }
+        users.next_id += 1;
+        let id = users.next_id;
+        users.users.insert(
+            id,
+            User {
+                id,
+                email,
+                passwordhash,
+                salt: String::new(),
+                is_admin: false,
+                email_verified: false,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<User, AuthError> {
+        lock(&self.users)
+            .find(&email)
+            .cloned()
+            .ok_or(AuthError::UserDoesNotExist)
+    }
+
+    async fn get_user_by_id(&self, id: i32) -> Result<User, AuthError> {
+        lock(&self.users)
+            .users
+            .get(&id)
+            .cloned()
+            .ok_or(AuthError::UserDoesNotExist)
+    }
+
+    async fn update_user_by_email(
+        &self,
+        filter: String,
+        email: Option<String>,
+        passwordhash: Option<String>,
+    ) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        let id = users.find(&filter).ok_or(AuthError::UserDoesNotExist)?.id;
+        if let Some(email) = &email {
+            if users.find(email).is_some_and(|user| user.id != id) {
+                return Err(AuthError::UserAlreadyExists);
+            }
+        }
+        let user = users
+            .users
+            .get_mut(&id)
+            .ok_or(AuthError::UserDoesNotExist)?;
+        if let Some(passwordhash) = passwordhash {
+            user.passwordhash = passwordhash;
+            user.salt = String::new();
+        }
+        if let Some(email) = email {
+            if user.email != email {
+                user.email = email;
+                user.email_verified = false;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_user_by_email(&self, filter: String) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        let id = users.find(&filter).ok_or(AuthError::UserDoesNotExist)?.id;
+        users.users.remove(&id);
+        users.user_roles.remove(&id);
+        users
+            .email_tokens
+            .retain(|_, (user_id, _, _)| *user_id != id);
+        users.totp.remove(&id);
+        users.recovery_codes.remove(&id);
+        users.passkeys.retain(|_, x| x.user_id != id);
+        Ok(())
+    }
+
+    async fn set_admin(&self, filter: String, is_admin: bool) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        let id = users.find(&filter).ok_or(AuthError::UserDoesNotExist)?.id;
+        if let Some(user) = users.users.get_mut(&id) {
+            user.is_admin = is_admin;
+        }
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, id: i32, email_verified: bool) -> Result<(), AuthError> {
+        lock(&self.users)
+            .users
+            .get_mut(&id)
+            .ok_or(AuthError::UserDoesNotExist)?
+            .email_verified = email_verified;
+        Ok(())
+    }
+
+    async fn add_email_token(
+        &self,
+        hash: String,
+        id: i32,
+        kind: EmailTokenKind,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        let now = unix_now();
+        users.email_tokens.retain(|_, (_, _, x)| *x > now);
+        users.email_tokens.insert(hash, (id, kind, expires_at));
+        Ok(())
+    }
+
+    async fn get_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        match lock(&self.users).email_tokens.get(&hash) {
+            Some(&(id, x, expires_at)) if x == kind && expires_at > now => Ok(Some(id)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn take_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        let mut users = lock(&self.users);
+        match users.email_tokens.get(&hash) {
+            Some(&(id, x, expires_at)) if x == kind => {
+                users.email_tokens.remove(&hash);
+                Ok((expires_at > now).then_some(id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_email_tokens(&self, id: i32, kind: EmailTokenKind) -> Result<(), AuthError> {
+        lock(&self.users)
+            .email_tokens
+            .retain(|_, (user_id, x, _)| *user_id != id || *x != kind);
+        Ok(())
+    }
+
+    async fn set_totp(&self, id: i32, secret: String) -> Result<(), AuthError> {
+        lock(&self.users).totp.insert(
+            id,
+            Totp {
+                secret,
+                confirmed: false,
+                last_step: 0,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_totp(&self, id: i32) -> Result<Option<Totp>, AuthError> {
+        Ok(lock(&self.users).totp.get(&id).cloned())
+    }
+
+    async fn confirm_totp(&self, id: i32) -> Result<(), AuthError> {
+        if let Some(x) = lock(&self.users).totp.get_mut(&id) {
+            x.confirmed = true;
+        }
+        Ok(())
+    }
+
+    async fn use_totp_step(&self, id: i32, step: u64) -> Result<bool, AuthError> {
+        match lock(&self.users).totp.get_mut(&id) {
+            Some(x) if x.last_step < step => {
+                x.last_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete_totp(&self, id: i32) -> Result<(), AuthError> {
+        lock(&self.users).totp.remove(&id);
+        Ok(())
+    }
+
+    async fn set_recovery_codes(&self, id: i32, hashes: Vec<String>) -> Result<(), AuthError> {
+        lock(&self.users)
+            .recovery_codes
+            .insert(id, hashes.into_iter().collect());
+        Ok(())
+    }
+
+    async fn take_recovery_code(&self, id: i32, hash: String) -> Result<bool, AuthError> {
+        Ok(lock(&self.users)
+            .recovery_codes
+            .get_mut(&id)
+            .is_some_and(|x| x.remove(&hash)))
+    }
+
+    async fn count_recovery_codes(&self, id: i32) -> Result<usize, AuthError> {
+        Ok(lock(&self.users)
+            .recovery_codes
+            .get(&id)
+            .map_or(0, BTreeSet::len))
+    }
+
+    async fn add_passkey(&self, passkey: Passkey) -> Result<(), AuthError> {
+        lock(&self.users)
+            .passkeys
+            .insert(passkey.id.clone(), passkey);
+        Ok(())
+    }
+
+    async fn get_passkey(&self, id: String) -> Result<Option<Passkey>, AuthError> {
+        Ok(lock(&self.users).passkeys.get(&id).cloned())
+    }
+
+    async fn get_user_passkeys(&self, user_id: i32) -> Result<Vec<Passkey>, AuthError> {
+        Ok(lock(&self.users)
+            .passkeys
+            .values()
+            .filter(|x| x.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn update_passkey_use(
+        &self,
+        id: String,
+        sign_count: u32,
+        last_used_at: u64,
+    ) -> Result<(), AuthError> {
+        if let Some(x) = lock(&self.users).passkeys.get_mut(&id) {
+            x.sign_count = sign_count;
+            x.last_used_at = Some(last_used_at);
+        }
+        Ok(())
+    }
+
+    async fn delete_passkey(&self, user_id: i32, id: String) -> Result<bool, AuthError> {
+        let mut users = lock(&self.users);
+        match users.passkeys.get(&id) {
+            Some(x) if x.user_id == user_id => Ok(users.passkeys.remove(&id).is_some()),
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_role(&self, role: String, permissions: Vec<String>) -> Result<(), AuthError> {
+        lock(&self.users)
+            .roles
+            .insert(role, permissions.into_iter().collect());
+        Ok(())
+    }
+
+    async fn delete_role(&self, role: String) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        users
+            .roles
+            .remove(&role)
+            .ok_or(AuthError::RoleDoesNotExist)?;
+        for roles in users.user_roles.values_mut() {
+            roles.remove(&role);
+        }
+        Ok(())
+    }
+
+    async fn assign_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        if !users.roles.contains_key(&role) {
+            return Err(AuthError::RoleDoesNotExist);
+        }
+        users.user_roles.entry(id).or_default().insert(role);
+        Ok(())
+    }
+
+    async fn revoke_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        if !users.roles.contains_key(&role) {
+            return Err(AuthError::RoleDoesNotExist);
+        }
+        if let Some(roles) = users.user_roles.get_mut(&id) {
+            roles.remove(&role);
+        }
+        Ok(())
+    }
+
+    async fn get_roles(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        Ok(lock(&self.users)
+            .user_roles
+            .get(&id)
+            .map(|roles| roles.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_permissions(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        let users = lock(&self.users);
+        let permissions: BTreeSet<&String> = users
+            .user_roles
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(|role| users.roles.get(role))
+            .flatten()
+            .collect();
+        Ok(permissions.into_iter().cloned().collect())
+    }
+
+    async fn add_signing_key(&self, key: SigningKey) -> Result<(), AuthError> {
+        lock(&self.users)
+            .signing_keys
+            .entry(key.kid.clone())
+            .or_insert(key);
+        Ok(())
+    }
+
+    async fn get_signing_keys(&self, now: u64) -> Result<Vec<SigningKey>, AuthError> {
+        let mut users = lock(&self.users);
+        users
+            .signing_keys
+            .retain(|_, x| x.expires_at.is_none_or(|x| x > now));
+        Ok(users.signing_keys.values().cloned().collect())
+    }
+
+    async fn retire_signing_key(
+        &self,
+        kid: String,
+        retired_at: u64,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        let mut users = lock(&self.users);
+        let key = users
+            .signing_keys
+            .get_mut(&kid)
+            .ok_or(AuthError::SigningKeyDoesNotExist)?;
+        key.retired_at.get_or_insert(retired_at);
+        key.expires_at.get_or_insert(expires_at);
+        Ok(())
+    }
+}
+
+struct Entry {
+    id: i32,
+    issued_at: u64,
+    expires_at: Option<Instant>,
+    session: Option<String>,
+    session_expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|x| x <= Instant::now())
+    }
+
+    fn to_token(&self) -> Token {
+        let now = Instant::now();
+        Token {
+            id: self.id,
+            issued_at: Some(self.issued_at),
+            expires_at: self
+                .expires_at
+                .map(|x| unix_now() + x.saturating_duration_since(now).as_secs()),
+            session: self.session.clone(),
+            session_expires_at: self.session_expires_at,
+        }
+    }
+}
+
+struct RefreshEntry {
+    token: RefreshToken,
+    expires_at: Instant,
+}
+
+struct ChallengeEntry {
+    challenge: MfaChallenge,
+    expires_at: Instant,
+}
+
+struct WebauthnEntry {
+    challenge: WebauthnChallenge,
+    expires_at: Instant,
+}
+
+struct FailuresEntry {
+    failures: LoginFailures,
+    expires_at: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct SessionEntry {
+    session: Session,
+    expires_at: Option<Instant>,
+}
+
+impl SessionEntry {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|x| x <= Instant::now())
+    }
+}
+
+/// Keeps tokens in memory, honoring expire times like redis does
+#[derive(Clone, Default)]
+pub struct MemoryTokenStore {
+    tokens: Arc<Mutex<HashMap<String, Entry>>>,
+    /// Denied access token ids and when they expire
+    denied: Arc<Mutex<HashMap<String, Instant>>>,
+    refresh_tokens: Arc<Mutex<HashMap<String, RefreshEntry>>>,
+    mfa_challenges: Arc<Mutex<HashMap<String, ChallengeEntry>>>,
+    webauthn_challenges: Arc<Mutex<HashMap<String, WebauthnEntry>>>,
+    login_failures: Arc<Mutex<HashMap<String, FailuresEntry>>>,
+    rate_limits: Arc<Mutex<HashMap<String, Bucket>>>,
+    sessions: Arc<Mutex<HashMap<String, SessionEntry>>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn get_token(&self, token: String) -> Result<Option<Token>, AuthError> {
+        let mut tokens = lock(&self.tokens);
+        match tokens.get(&token) {
+            Some(x) if x.is_expired() => {
+                tokens.remove(&token);
+                Ok(None)
+            }
+            x => Ok(x.map(Entry::to_token)),
+        }
+    }
+
+    async fn set_token(
+        &self,
+        token: String,
+        id: i32,
+        session: Option<String>,
+        session_expires_at: Option<u64>,
+        expire: Option<usize>,
+    ) -> Result<(), AuthError> {
+        let expires_at = expire.map(|x| Instant::now() + Duration::from_secs(x as u64));
+        let mut tokens = lock(&self.tokens);
+        // expired tokens are pruned here rather than on every lookup
+        tokens.retain(|_, x| !x.is_expired());
+        tokens.insert(
+            token,
+            Entry {
+                id,
+                issued_at: unix_now(),
+                expires_at,
+                session,
+                session_expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    async fn expire_token(&self, token: String, expire: usize) -> Result<(), AuthError> {
+        if let Some(x) = lock(&self.tokens).get_mut(&token) {
+            x.expires_at = Some(Instant::now() + Duration::from_secs(expire as u64));
+        }
+        Ok(())
+    }
+
+    async fn delete_token(&self, token: String) -> Result<bool, AuthError> {
+        Ok(lock(&self.tokens)
+            .remove(&token)
+            .is_some_and(|x| !x.is_expired()))
+    }
+
+    async fn delete_user_tokens(&self, id: i32) -> Result<(), AuthError> {
+        lock(&self.tokens).retain(|_, x| x.id != id);
+        lock(&self.refresh_tokens).retain(|_, x| x.token.id != id);
+        lock(&self.sessions).retain(|_, x| x.session.user_id != id);
+        Ok(())
+    }
+
+    async fn deny_token(&self, jti: String, expire: usize) -> Result<(), AuthError> {
+        let mut denied = lock(&self.denied);
+        let now = Instant::now();
+        denied.retain(|_, x| *x > now);
+        denied.insert(jti, now + Duration::from_secs(expire as u64));
+        Ok(())
+    }
+
+    async fn is_token_denied(&self, jti: String) -> Result<bool, AuthError> {
+        Ok(lock(&self.denied)
+            .get(&jti)
+            .is_some_and(|x| *x > Instant::now()))
+    }
+
+    async fn set_refresh_token(
+        &self,
+        token: String,
+        id: i32,
+        session: String,
+        session_expires_at: Option<u64>,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let mut refresh_tokens = lock(&self.refresh_tokens);
+        let now = Instant::now();
+        refresh_tokens.retain(|_, x| x.expires_at > now);
+        refresh_tokens.insert(
+            token,
+            RefreshEntry {
+                token: RefreshToken {
+                    id,
+                    session,
+                    used: false,
+                    session_expires_at,
+                },
+                expires_at: now + Duration::from_secs(expire as u64),
+            },
+        );
+        Ok(())
+    }
+
+    async fn use_refresh_token(&self, token: String) -> Result<Option<RefreshToken>, AuthError> {
+        let mut refresh_tokens = lock(&self.refresh_tokens);
+        match refresh_tokens.get_mut(&token) {
+            Some(x) if x.expires_at > Instant::now() => {
+                let token = x.token.clone();
+                x.token.used = true;
+                Ok(Some(token))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn set_mfa_challenge(
+        &self,
+        challenge: String,
+        id: i32,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let mut challenges = lock(&self.mfa_challenges);
+        let now = Instant::now();
+        challenges.retain(|_, x| x.expires_at > now);
+        challenges.insert(
+            challenge,
+            ChallengeEntry {
+                challenge: MfaChallenge { id, attempts: 0 },
+                expires_at: now + Duration::from_secs(expire as u64),
+            },
+        );
+        Ok(())
+    }
+
+    async fn use_mfa_challenge(
+        &self,
+        challenge: String,
+    ) -> Result<Option<MfaChallenge>, AuthError> {
+        match lock(&self.mfa_challenges).get_mut(&challenge) {
+            Some(x) if x.expires_at > Instant::now() => {
+                x.challenge.attempts += 1;
+                Ok(Some(x.challenge.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_mfa_challenge(&self, challenge: String) -> Result<(), AuthError> {
+        lock(&self.mfa_challenges).remove(&challenge);
+        Ok(())
+    }
+
+    async fn set_webauthn_challenge(
+        &self,
+        challenge: String,
+        state: WebauthnChallenge,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let mut challenges = lock(&self.webauthn_challenges);
+        let now = Instant::now();
+        challenges.retain(|_, x| x.expires_at > now);
+        challenges.insert(
+            challenge,
+            WebauthnEntry {
+                challenge: state,
+                expires_at: now + Duration::from_secs(expire as u64),
+            },
+        );
+        Ok(())
+    }
+
+    async fn take_webauthn_challenge(
+        &self,
+        challenge: String,
+    ) -> Result<Option<WebauthnChallenge>, AuthError> {
+        Ok(lock(&self.webauthn_challenges)
+            .remove(&challenge)
+            .filter(|x| x.expires_at > Instant::now())
+            .map(|x| x.challenge))
+    }
+
+    async fn add_login_failure(&self, key: String, expire: usize) -> Result<u32, AuthError> {
+        let mut failures = lock(&self.login_failures);
+        let now = Instant::now();
+        failures.retain(|_, x| x.expires_at > now);
+        let entry = failures.entry(key).or_insert_with(|| FailuresEntry {
+            failures: LoginFailures::default(),
+            expires_at: now,
+        });
+        entry.failures.count += 1;
+        entry.expires_at = now + Duration::from_secs(expire as u64);
+        Ok(entry.failures.count)
+    }
+
+    async fn lock_login(&self, key: String, until: u64, expire: usize) -> Result<(), AuthError> {
+        let mut failures = lock(&self.login_failures);
+        let now = Instant::now();
+        failures.retain(|_, x| x.expires_at > now);
+        let entry = failures.entry(key).or_insert_with(|| FailuresEntry {
+            failures: LoginFailures::default(),
+            expires_at: now,
+        });
+        entry.failures.locked_until = Some(until);
+        entry.expires_at = now + Duration::from_secs(expire as u64);
+        Ok(())
+    }
+
+    async fn get_login_failures(&self, key: String) -> Result<LoginFailures, AuthError> {
+        Ok(lock(&self.login_failures)
+            .get(&key)
+            .filter(|x| x.expires_at > Instant::now())
+            .map(|x| x.failures.clone())
+            .unwrap_or_default())
+    }
+
+    async fn clear_login_failures(&self, key: String) -> Result<(), AuthError> {
+        lock(&self.login_failures).remove(&key);
+        Ok(())
+    }
+
+    async fn take_rate_limit(
+        &self,
+        key: String,
+        burst: u32,
+        per_second: f64,
+    ) -> Result<Option<u64>, AuthError> {
+        let mut buckets = lock(&self.rate_limits);
+        let now = Instant::now();
+        let burst = burst as f64;
+        if !buckets.contains_key(&key) {
+            // buckets that filled up again are the same as new ones
+            buckets.retain(|_, x| (now - x.updated_at).as_secs_f64() * per_second < burst);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        let elapsed = (now - bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(None);
+        }
+        Ok(Some(
+            ((1.0 - bucket.tokens) * 1000.0 / per_second).ceil() as u64
+        ))
+    }
+
+    async fn create_session(&self, session: Session) -> Result<(), AuthError> {
+        let mut sessions = lock(&self.sessions);
+        sessions.retain(|_, x| !x.is_expired());
+        sessions.insert(
+            session.id.clone(),
+            SessionEntry {
+                session,
+                expires_at: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn touch_session(&self, id: String, expire: Option<usize>) -> Result<(), AuthError> {
+        if let Some(x) = lock(&self.sessions).get_mut(&id) {
+            // an expired session that was not pruned yet stays ended
+            if x.is_expired() {
+                return Ok(());
+            }
+            x.session.last_used_at = unix_now();
+            if let Some(expire) = expire {
+                x.expires_at = Some(Instant::now() + Duration::from_secs(expire as u64));
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, AuthError> {
+        let mut sessions = lock(&self.sessions);
+        sessions.retain(|_, x| !x.is_expired());
+        Ok(sessions
+            .values()
+            .filter(|x| x.session.user_id == user_id)
+            .map(|x| x.session.clone())
+            .collect())
+    }
+
+    async fn delete_session(&self, id: String) -> Result<(), AuthError> {
+        lock(&self.tokens).retain(|_, x| x.session.as_ref() != Some(&id));
+        lock(&self.refresh_tokens).retain(|_, x| x.token.session != id);
+        lock(&self.sessions).remove(&id);
+        Ok(())
+    }
+}
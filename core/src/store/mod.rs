@@ -0,0 +1,405 @@
+use async_trait::async_trait;
+
+use crate::{AuthError, SigningKey};
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteUserStore;
+pub use self::{
+    memory::{MemoryTokenStore, MemoryUserStore},
+    postgres::PostgresUserStore,
+    redis::RedisTokenStore,
+};
+
+mod memory;
+mod postgres;
+mod redis;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// A user as stored by a [`UserStore`]
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct User {
+    pub id: i32,
+    pub email: String,
+    pub passwordhash: String,
+    /// Only set for legacy SHA-256 hashes, PHC hashes carry their own salt
+    pub salt: String,
+    /// Admins can update and delete other users
+    pub is_admin: bool,
+    /// Whether the user proved they own their email address
+    pub email_verified: bool,
+}
+
+/// What a single use token sent by email is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmailTokenKind {
+    VerifyEmail,
+    PasswordReset,
+}
+
+impl EmailTokenKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailTokenKind::VerifyEmail => "verify_email",
+            EmailTokenKind::PasswordReset => "password_reset",
+        }
+    }
+}
+
+/// The TOTP secret of a user as stored by a [`UserStore`]
+#[derive(Clone, Debug)]
+pub struct Totp {
+    /// Base32 encoded secret shared with the user's authenticator app
+    pub secret: String,
+    /// Whether the user proved their app has the secret by entering a code,
+    /// codes are only asked for at login once they have
+    pub confirmed: bool,
+    /// Time step of the last code that was accepted, so no code is accepted
+    /// twice
+    pub last_step: u64,
+}
+
+/// A WebAuthn credential of a user as stored by a [`UserStore`], times are
+/// in unix seconds
+#[derive(Clone, Debug)]
+pub struct Passkey {
+    /// Credential id, base64url encoded
+    pub id: String,
+    pub user_id: i32,
+    /// PEM encoded
+    pub public_key: String,
+    /// COSE algorithm id of the key
+    pub algorithm: i32,
+    /// How many signatures the authenticator reported making, zero if it
+    /// does not count them
+    pub sign_count: u32,
+    /// Name the user gave the passkey
+    pub name: Option<String>,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+/// A login token as stored by a [`TokenStore`], times are in unix seconds
+#[derive(Clone, Debug)]
+pub struct Token {
+    /// Id of the user the token belongs to
+    pub id: i32,
+    /// Unknown for tokens issued before issue times were recorded
+    pub issued_at: Option<u64>,
+    /// `None` if the token does not expire
+    pub expires_at: Option<u64>,
+    /// Id of the session the token was issued in, unknown for tokens issued
+    /// before sessions were recorded
+    pub session: Option<String>,
+    /// When the login the token came from ends, the token is never extended
+    /// past it
+    pub session_expires_at: Option<u64>,
+}
+
+/// A refresh token as stored by a [`TokenStore`]
+#[derive(Clone, Debug)]
+pub struct RefreshToken {
+    /// Id of the user the token belongs to
+    pub id: i32,
+    /// Id of the session the token was issued in, the tokens it is
+    /// exchanged for join the same session
+    pub session: String,
+    /// Whether the token was already exchanged for new tokens
+    pub used: bool,
+    /// When the login the token came from ends, carried over to the tokens
+    /// it is exchanged for
+    pub session_expires_at: Option<u64>,
+}
+
+/// A login waiting for a second factor, as stored by a [`TokenStore`]
+#[derive(Clone, Debug)]
+pub struct MfaChallenge {
+    /// Id of the user that logged in
+    pub id: i32,
+    /// How many codes were tried for the challenge, counting the current one
+    pub attempts: u32,
+}
+
+/// Failed logins counted for an email or an IP, as stored by a [`TokenStore`]
+#[derive(Clone, Debug, Default)]
+pub struct LoginFailures {
+    /// Failures since the counter was last forgotten or cleared
+    pub count: u32,
+    /// Unix time logins are refused until
+    pub locked_until: Option<u64>,
+}
+
+/// Which WebAuthn ceremony a challenge was issued for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebauthnCeremony {
+    Registration,
+    Authentication,
+}
+
+impl WebauthnCeremony {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebauthnCeremony::Registration => "registration",
+            WebauthnCeremony::Authentication => "authentication",
+        }
+    }
+}
+
+/// A challenge waiting to be signed by an authenticator, as stored by a
+/// [`TokenStore`]
+#[derive(Clone, Debug)]
+pub struct WebauthnChallenge {
+    pub ceremony: WebauthnCeremony,
+    /// The user registering a passkey, or logging in if they gave their
+    /// email first
+    pub user_id: Option<i32>,
+}
+
+/// A login and every token issued from it, as stored by a [`TokenStore`].
+/// Times are in unix seconds.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    /// Id of the user that logged in
+    pub user_id: i32,
+    pub created_at: u64,
+    /// When a token of the session was last verified or refreshed
+    pub last_used_at: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Name the client gave the device it logged in from
+    pub device: Option<String>,
+}
+
+/// Persistent storage for user accounts, their roles and the keys access
+/// tokens are signed with
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn create_user(&self, email: String, passwordhash: String) -> Result<(), AuthError>;
+
+    async fn get_user_by_email(&self, email: String) -> Result<User, AuthError>;
+
+    async fn get_user_by_id(&self, id: i32) -> Result<User, AuthError>;
+
+    /// Changing the email marks it as unverified
+    async fn update_user_by_email(
+        &self,
+        filter: String,
+        email: Option<String>,
+        passwordhash: Option<String>,
+    ) -> Result<(), AuthError>;
+
+    async fn delete_user_by_email(&self, filter: String) -> Result<(), AuthError>;
+
+    async fn set_admin(&self, filter: String, is_admin: bool) -> Result<(), AuthError>;
+
+    async fn set_email_verified(&self, id: i32, email_verified: bool) -> Result<(), AuthError>;
+
+    /// Store a token sent by email by its hash, expired tokens are dropped
+    async fn add_email_token(
+        &self,
+        hash: String,
+        id: i32,
+        kind: EmailTokenKind,
+        expires_at: u64,
+    ) -> Result<(), AuthError>;
+
+    /// Return the id of a token's user if it is of the given kind and has
+    /// not expired by `now`, keeping the token
+    async fn get_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError>;
+
+    /// Delete a token, returning the id of its user if it is of the given
+    /// kind and has not expired by `now`
+    async fn take_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError>;
+
+    async fn delete_email_tokens(&self, id: i32, kind: EmailTokenKind) -> Result<(), AuthError>;
+
+    /// Store a new unconfirmed TOTP secret for a user, replacing the one
+    /// they had
+    async fn set_totp(&self, id: i32, secret: String) -> Result<(), AuthError>;
+
+    async fn get_totp(&self, id: i32) -> Result<Option<Totp>, AuthError>;
+
+    async fn confirm_totp(&self, id: i32) -> Result<(), AuthError>;
+
+    /// Record the time step of an accepted code, returns false if a code of
+    /// the same or a later step was accepted already
+    async fn use_totp_step(&self, id: i32, step: u64) -> Result<bool, AuthError>;
+
+    async fn delete_totp(&self, id: i32) -> Result<(), AuthError>;
+
+    /// Replace every recovery code of a user with new ones, by their hashes
+    async fn set_recovery_codes(&self, id: i32, hashes: Vec<String>) -> Result<(), AuthError>;
+
+    /// Delete a recovery code of a user by its hash, returns false if they
+    /// did not have it
+    async fn take_recovery_code(&self, id: i32, hash: String) -> Result<bool, AuthError>;
+
+    async fn count_recovery_codes(&self, id: i32) -> Result<usize, AuthError>;
+
+    async fn add_passkey(&self, passkey: Passkey) -> Result<(), AuthError>;
+
+    async fn get_passkey(&self, id: String) -> Result<Option<Passkey>, AuthError>;
+
+    async fn get_user_passkeys(&self, user_id: i32) -> Result<Vec<Passkey>, AuthError>;
+
+    /// Record a login with a passkey
+    async fn update_passkey_use(
+        &self,
+        id: String,
+        sign_count: u32,
+        last_used_at: u64,
+    ) -> Result<(), AuthError>;
+
+    /// Returns false if the user did not have the passkey
+    async fn delete_passkey(&self, user_id: i32, id: String) -> Result<bool, AuthError>;
+
+    /// Create a role, or replace the permissions of an existing one
+    async fn set_role(&self, role: String, permissions: Vec<String>) -> Result<(), AuthError>;
+
+    /// Delete a role, removing it from every user that has it
+    async fn delete_role(&self, role: String) -> Result<(), AuthError>;
+
+    async fn assign_role(&self, id: i32, role: String) -> Result<(), AuthError>;
+
+    async fn revoke_role(&self, id: i32, role: String) -> Result<(), AuthError>;
+
+    /// Names of the roles a user has, sorted
+    async fn get_roles(&self, id: i32) -> Result<Vec<String>, AuthError>;
+
+    /// Every permission granted by the user's roles, sorted and deduplicated
+    async fn get_permissions(&self, id: i32) -> Result<Vec<String>, AuthError>;
+
+    /// Keys that already exist are left as they are
+    async fn add_signing_key(&self, key: SigningKey) -> Result<(), AuthError>;
+
+    /// Keys that have not expired by `now`
+    async fn get_signing_keys(&self, now: u64) -> Result<Vec<SigningKey>, AuthError>;
+
+    /// Keys that were already retired keep their times
+    async fn retire_signing_key(
+        &self,
+        kid: String,
+        retired_at: u64,
+        expires_at: u64,
+    ) -> Result<(), AuthError>;
+}
+
+/// Storage for login tokens, mapping each token to the id of its user
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn get_token(&self, token: String) -> Result<Option<Token>, AuthError>;
+
+    /// Store a new token, recording the current time as its issue time
+    async fn set_token(
+        &self,
+        token: String,
+        id: i32,
+        session: Option<String>,
+        session_expires_at: Option<u64>,
+        expire: Option<usize>,
+    ) -> Result<(), AuthError>;
+
+    async fn expire_token(&self, token: String, expire: usize) -> Result<(), AuthError>;
+
+    /// Returns false if the token did not exist
+    async fn delete_token(&self, token: String) -> Result<bool, AuthError>;
+
+    /// Delete every token and session of a user, refresh tokens included
+    async fn delete_user_tokens(&self, id: i32) -> Result<(), AuthError>;
+
+    async fn set_refresh_token(
+        &self,
+        token: String,
+        id: i32,
+        session: String,
+        session_expires_at: Option<u64>,
+        expire: usize,
+    ) -> Result<(), AuthError>;
+
+    /// Mark a refresh token as used, returning it as it was before. Used
+    /// tokens are kept until they expire so reuse can be detected.
+    async fn use_refresh_token(&self, token: String) -> Result<Option<RefreshToken>, AuthError>;
+
+    /// Store a login waiting for a second factor
+    async fn set_mfa_challenge(
+        &self,
+        challenge: String,
+        id: i32,
+        expire: usize,
+    ) -> Result<(), AuthError>;
+
+    /// Count an attempt at answering a challenge, returning it with the
+    /// attempt counted
+    async fn use_mfa_challenge(&self, challenge: String)
+        -> Result<Option<MfaChallenge>, AuthError>;
+
+    async fn delete_mfa_challenge(&self, challenge: String) -> Result<(), AuthError>;
+
+    async fn set_webauthn_challenge(
+        &self,
+        challenge: String,
+        state: WebauthnChallenge,
+        expire: usize,
+    ) -> Result<(), AuthError>;
+
+    /// Delete a challenge, returning it if it had not expired. Each challenge
+    /// can only be answered once.
+    async fn take_webauthn_challenge(
+        &self,
+        challenge: String,
+    ) -> Result<Option<WebauthnChallenge>, AuthError>;
+
+    /// Count a failed login for a key, returning the failures counted so
+    /// far. The counter is forgotten `expire` seconds after the last failure.
+    async fn add_login_failure(&self, key: String, expire: usize) -> Result<u32, AuthError>;
+
+    /// Refuse logins for a key until unix time `until`, keeping its counter
+    /// for `expire` seconds
+    async fn lock_login(&self, key: String, until: u64, expire: usize) -> Result<(), AuthError>;
+
+    async fn get_login_failures(&self, key: String) -> Result<LoginFailures, AuthError>;
+
+    /// Forget the failures and lock of a key
+    async fn clear_login_failures(&self, key: String) -> Result<(), AuthError>;
+
+    /// Take a request from the token bucket of a key, which holds up to
+    /// `burst` requests and gets `per_second` back every second. Returns how
+    /// many milliseconds until the next request if the bucket is empty.
+    async fn take_rate_limit(
+        &self,
+        key: String,
+        burst: u32,
+        per_second: f64,
+    ) -> Result<Option<u64>, AuthError>;
+
+    /// Store a new session, it does not expire until it is touched with an
+    /// expire time
+    async fn create_session(&self, session: Session) -> Result<(), AuthError>;
+
+    /// Record the current time as the last time a session was used, and
+    /// make it expire in `expire` seconds if given. Sessions that do not
+    /// exist are left alone.
+    async fn touch_session(&self, id: String, expire: Option<usize>) -> Result<(), AuthError>;
+
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, AuthError>;
+
+    /// Delete a session along with every token issued in it
+    async fn delete_session(&self, id: String) -> Result<(), AuthError>;
+
+    /// Deny an access token by its id, for `expire` seconds until it
+    /// would have expired anyway
+    async fn deny_token(&self, jti: String, expire: usize) -> Result<(), AuthError>;
+
+    async fn is_token_denied(&self, jti: String) -> Result<bool, AuthError>;
+}
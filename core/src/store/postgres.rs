@@ -0,0 +1,963 @@
+use async_trait::async_trait;
+use sqlx::{Pool, Postgres};
+
+use super::{EmailTokenKind, Passkey, Totp, User, UserStore};
+use crate::{
+    util::{self, connect, unix_now},
+    AuthError, SigningKey,
+};
+
+/// Whether a query failed because a unique column already holds the value
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|x| x.code())
+        .is_some_and(|x| x == "23505")
+}
+
+#[derive(Clone)]
+pub struct PostgresUserStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresUserStore {
+    pub async fn new(postgres_url: String) -> Result<Self, AuthError> {
+        let pool = util::get_pool(postgres_url).await?;
+        util::init_db(&pool).await?;
+        Ok(PostgresUserStore { pool })
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresUserStore {
+    async fn create_user(&self, email: String, passwordhash: String) -> Result<(), AuthError> {
+        // the salt is stored inside of the hash
+        let salt = String::new();
+        match sqlx::query!(
+            r#"
+            INSERT INTO users ( email, passwordhash, salt )
+            VALUES ( $1, $2, $3 );
+            "#,
+            email,
+            passwordhash,
+            salt
+        )
+        .execute(&self.pool)
+        .await
+        {
+            Ok(ok) => ok,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        Ok(())
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<User, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query_as!(
+            User,
+            r#"
+            SELECT *
+            FROM users
+            WHERE email = $1;
+            "#,
+            email
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(AuthError::UserDoesNotExist),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn get_user_by_id(&self, id: i32) -> Result<User, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query_as!(
+            User,
+            r#"
+            SELECT *
+            FROM users
+            WHERE id = $1;
+            "#,
+            id
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(AuthError::UserDoesNotExist),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn update_user_by_email(
+        &self,
+        filter: String,
+        email: Option<String>,
+        passwordhash: Option<String>,
+    ) -> Result<(), AuthError> {
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        // a taken email leaves the password unchanged as well
+        let mut tx = match self.pool.begin().await {
+            Ok(tx) => tx,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        if let Some(passwordhash) = passwordhash {
+            // clear any legacy salt, it is stored inside of the hash now
+            let salt = String::new();
+            match sqlx::query!(
+                r#"
+                UPDATE "users"
+                SET passwordhash = $1,
+                    salt         = $2
+                WHERE email      = $3;
+                "#,
+                passwordhash,
+                salt,
+                filter
+            )
+            .execute(&mut tx)
+            .await
+            {
+                Ok(ok) => ok,
+                Err(err) => return Err(AuthError::PostgresError(err)),
+            };
+        };
+
+        if let Some(email) = email {
+            match sqlx::query!(
+                r#"
+                UPDATE "users"
+                SET email          = $1,
+                    email_verified = email_verified AND email = $1
+                WHERE email        = $2;
+                "#,
+                email,
+                filter
+            )
+            .execute(&mut tx)
+            .await
+            {
+                Ok(ok) => ok,
+                Err(err) if is_unique_violation(&err) => return Err(AuthError::UserAlreadyExists),
+                Err(err) => return Err(AuthError::PostgresError(err)),
+            };
+        };
+
+        tx.commit().await.map_err(AuthError::PostgresError)
+    }
+
+    async fn delete_user_by_email(&self, filter: String) -> Result<(), AuthError> {
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        // rows of other tables only refer to the user by id, so they go
+        // together or not at all
+        let mut tx = match self.pool.begin().await {
+            Ok(tx) => tx,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "user_roles"
+            WHERE user_id IN (SELECT id FROM users WHERE email = $1);
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE user_id IN (SELECT id FROM users WHERE email = $1);
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "totp_secrets"
+            WHERE user_id IN (SELECT id FROM users WHERE email = $1);
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "recovery_codes"
+            WHERE user_id IN (SELECT id FROM users WHERE email = $1);
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "passkeys"
+            WHERE user_id IN (SELECT id FROM users WHERE email = $1);
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "users"
+            WHERE email = $1;
+            "#,
+            filter
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        tx.commit().await.map_err(AuthError::PostgresError)
+    }
+
+    async fn set_admin(&self, filter: String, is_admin: bool) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        match sqlx::query!(
+            r#"
+            UPDATE "users"
+            SET is_admin = $1
+            WHERE email  = $2;
+            "#,
+            is_admin,
+            filter
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, id: i32, email_verified: bool) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            UPDATE "users"
+            SET email_verified = $1
+            WHERE id           = $2;
+            "#,
+            email_verified,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::UserDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn add_email_token(
+        &self,
+        hash: String,
+        id: i32,
+        kind: EmailTokenKind,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE expires_at <= $1;
+            "#,
+            unix_now() as i64
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        match sqlx::query!(
+            r#"
+            INSERT INTO email_tokens ( token_hash, user_id, kind, expires_at )
+            VALUES ( $1, $2, $3, $4 );
+            "#,
+            hash,
+            id,
+            kind.as_str(),
+            expires_at as i64
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn get_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            SELECT user_id
+            FROM "email_tokens"
+            WHERE token_hash = $1
+              AND kind       = $2
+              AND expires_at > $3;
+            "#,
+            hash,
+            kind.as_str(),
+            now as i64
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(row) => Ok(row.map(|x| x.user_id)),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn take_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE token_hash = $1
+              AND kind       = $2
+            RETURNING user_id, expires_at;
+            "#,
+            hash,
+            kind.as_str()
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(Some(row)) if row.expires_at as u64 > now => Ok(Some(row.user_id)),
+            Ok(_) => Ok(None),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn delete_email_tokens(&self, id: i32, kind: EmailTokenKind) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE user_id = $1
+              AND kind    = $2;
+            "#,
+            id,
+            kind.as_str()
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn set_totp(&self, id: i32, secret: String) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            INSERT INTO totp_secrets ( user_id, secret )
+            VALUES ( $1, $2 )
+            ON CONFLICT ( user_id ) DO UPDATE
+            SET secret    = $2,
+                confirmed = FALSE,
+                last_step = 0;
+            "#,
+            id,
+            secret
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn get_totp(&self, id: i32) -> Result<Option<Totp>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            SELECT secret, confirmed, last_step
+            FROM totp_secrets
+            WHERE user_id = $1;
+            "#,
+            id
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(row) => Ok(row.map(|row| Totp {
+                secret: row.secret,
+                confirmed: row.confirmed,
+                last_step: row.last_step as u64,
+            })),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn confirm_totp(&self, id: i32) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            UPDATE "totp_secrets"
+            SET confirmed = TRUE
+            WHERE user_id = $1;
+            "#,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn use_totp_step(&self, id: i32, step: u64) -> Result<bool, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            UPDATE "totp_secrets"
+            SET last_step = $1
+            WHERE user_id = $2
+              AND last_step < $1;
+            "#,
+            step as i64,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn delete_totp(&self, id: i32) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "totp_secrets"
+            WHERE user_id = $1;
+            "#,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn set_recovery_codes(&self, id: i32, hashes: Vec<String>) -> Result<(), AuthError> {
+        let mut tx = match self.pool.begin().await {
+            Ok(tx) => tx,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        match sqlx::query!(
+            r#"
+            DELETE FROM "recovery_codes"
+            WHERE user_id = $1;
+            "#,
+            id
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        match sqlx::query!(
+            r#"
+            INSERT INTO recovery_codes ( code_hash, user_id )
+            SELECT code_hash, $1 FROM UNNEST($2::TEXT[]) AS code_hash;
+            "#,
+            id,
+            &hashes[..]
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        tx.commit().await.map_err(AuthError::PostgresError)
+    }
+
+    async fn take_recovery_code(&self, id: i32, hash: String) -> Result<bool, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "recovery_codes"
+            WHERE user_id   = $1
+              AND code_hash = $2;
+            "#,
+            id,
+            hash
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn count_recovery_codes(&self, id: i32) -> Result<usize, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM recovery_codes
+            WHERE user_id = $1;
+            "#,
+            id
+        )
+        .fetch_one(&mut conn)
+        .await
+        {
+            Ok(count) => Ok(count as usize),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn add_passkey(&self, passkey: Passkey) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            INSERT INTO passkeys ( credential_id, user_id, public_key, algorithm, sign_count, name, created_at, last_used_at )
+            VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 );
+            "#,
+            passkey.id,
+            passkey.user_id,
+            passkey.public_key,
+            passkey.algorithm,
+            passkey.sign_count as i64,
+            passkey.name,
+            passkey.created_at as i64,
+            passkey.last_used_at.map(|x| x as i64)
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn get_passkey(&self, id: String) -> Result<Option<Passkey>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            SELECT *
+            FROM passkeys
+            WHERE credential_id = $1;
+            "#,
+            id
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(row) => Ok(row.map(|row| Passkey {
+                id: row.credential_id,
+                user_id: row.user_id,
+                public_key: row.public_key,
+                algorithm: row.algorithm,
+                sign_count: row.sign_count as u32,
+                name: row.name,
+                created_at: row.created_at as u64,
+                last_used_at: row.last_used_at.map(|x| x as u64),
+            })),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn get_user_passkeys(&self, user_id: i32) -> Result<Vec<Passkey>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            SELECT *
+            FROM passkeys
+            WHERE user_id = $1
+            ORDER BY created_at;
+            "#,
+            user_id
+        )
+        .fetch_all(&mut conn)
+        .await
+        {
+            Ok(rows) => Ok(rows
+                .into_iter()
+                .map(|row| Passkey {
+                    id: row.credential_id,
+                    user_id: row.user_id,
+                    public_key: row.public_key,
+                    algorithm: row.algorithm,
+                    sign_count: row.sign_count as u32,
+                    name: row.name,
+                    created_at: row.created_at as u64,
+                    last_used_at: row.last_used_at.map(|x| x as u64),
+                })
+                .collect()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn update_passkey_use(
+        &self,
+        id: String,
+        sign_count: u32,
+        last_used_at: u64,
+    ) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            UPDATE "passkeys"
+            SET sign_count    = $1,
+                last_used_at  = $2
+            WHERE credential_id = $3;
+            "#,
+            sign_count as i64,
+            last_used_at as i64,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn delete_passkey(&self, user_id: i32, id: String) -> Result<bool, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            DELETE FROM "passkeys"
+            WHERE user_id       = $1
+              AND credential_id = $2;
+            "#,
+            user_id,
+            id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn set_role(&self, role: String, permissions: Vec<String>) -> Result<(), AuthError> {
+        let mut tx = match self.pool.begin().await {
+            Ok(tx) => tx,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        let role_id = match sqlx::query_scalar!(
+            r#"
+            INSERT INTO roles ( name )
+            VALUES ( $1 )
+            ON CONFLICT ( name ) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id;
+            "#,
+            role
+        )
+        .fetch_one(&mut tx)
+        .await
+        {
+            Ok(id) => id,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "role_permissions"
+            WHERE role_id = $1;
+            "#,
+            role_id
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        match sqlx::query!(
+            r#"
+            INSERT INTO role_permissions ( role_id, permission )
+            SELECT $1, permission FROM UNNEST($2::TEXT[]) AS permission
+            ON CONFLICT DO NOTHING;
+            "#,
+            role_id,
+            &permissions[..]
+        )
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        tx.commit().await.map_err(AuthError::PostgresError)
+    }
+
+    async fn delete_role(&self, role: String) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+
+        // assignments and permissions are removed by the foreign keys
+        match sqlx::query!(
+            r#"
+            DELETE FROM "roles"
+            WHERE name = $1;
+            "#,
+            role
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::RoleDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+
+    async fn assign_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        let role_id = self.get_role_id(role).await?;
+
+        match sqlx::query!(
+            r#"
+            INSERT INTO user_roles ( user_id, role_id )
+            VALUES ( $1, $2 )
+            ON CONFLICT DO NOTHING;
+            "#,
+            id,
+            role_id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn revoke_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        let role_id = self.get_role_id(role).await?;
+
+        match sqlx::query!(
+            r#"
+            DELETE FROM "user_roles"
+            WHERE user_id = $1 AND role_id = $2;
+            "#,
+            id,
+            role_id
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn get_roles(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        sqlx::query_scalar!(
+            r#"
+            SELECT roles.name
+            FROM user_roles
+            JOIN roles ON roles.id = user_roles.role_id
+            WHERE user_roles.user_id = $1
+            ORDER BY roles.name;
+            "#,
+            id
+        )
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AuthError::PostgresError)
+    }
+
+    async fn get_permissions(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT role_permissions.permission
+            FROM user_roles
+            JOIN role_permissions ON role_permissions.role_id = user_roles.role_id
+            WHERE user_roles.user_id = $1
+            ORDER BY role_permissions.permission;
+            "#,
+            id
+        )
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AuthError::PostgresError)
+    }
+
+    async fn add_signing_key(&self, key: SigningKey) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            INSERT INTO signing_keys ( kid, algorithm, private_key, public_key, created_at, retired_at, expires_at )
+            VALUES ( $1, $2, $3, $4, $5, $6, $7 )
+            ON CONFLICT ( kid ) DO NOTHING;
+            "#,
+            key.kid,
+            key.algorithm.to_string(),
+            key.private_key,
+            key.public_key,
+            key.created_at as i64,
+            key.retired_at.map(|x| x as i64),
+            key.expires_at.map(|x| x as i64)
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn get_signing_keys(&self, now: u64) -> Result<Vec<SigningKey>, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        let rows = match sqlx::query!(
+            r#"
+            SELECT *
+            FROM signing_keys
+            WHERE expires_at IS NULL OR expires_at > $1;
+            "#,
+            now as i64
+        )
+        .fetch_all(&mut conn)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(err) => return Err(AuthError::PostgresError(err)),
+        };
+        rows.into_iter()
+            .map(|row| {
+                Ok(SigningKey {
+                    kid: row.kid,
+                    algorithm: row.algorithm.parse().map_err(AuthError::JwtError)?,
+                    private_key: row.private_key,
+                    public_key: row.public_key,
+                    created_at: row.created_at as u64,
+                    retired_at: row.retired_at.map(|x| x as u64),
+                    expires_at: row.expires_at.map(|x| x as u64),
+                })
+            })
+            .collect()
+    }
+
+    async fn retire_signing_key(
+        &self,
+        kid: String,
+        retired_at: u64,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query!(
+            r#"
+            UPDATE "signing_keys"
+            SET retired_at = COALESCE(retired_at, $1),
+                expires_at = COALESCE(expires_at, $2)
+            WHERE kid      = $3;
+            "#,
+            retired_at as i64,
+            expires_at as i64,
+            kid
+        )
+        .execute(&mut conn)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::SigningKeyDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+}
+
+impl PostgresUserStore {
+    async fn get_role_id(&self, role: String) -> Result<i32, AuthError> {
+        let mut conn = connect(&self.pool).await?;
+        match sqlx::query_scalar!(
+            r#"
+            SELECT id
+            FROM roles
+            WHERE name = $1;
+            "#,
+            role
+        )
+        .fetch_optional(&mut conn)
+        .await
+        {
+            Ok(Some(id)) => Ok(id),
+            Ok(None) => Err(AuthError::RoleDoesNotExist),
+            Err(err) => Err(AuthError::PostgresError(err)),
+        }
+    }
+}
@@ -0,0 +1,707 @@
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    LoginFailures, MfaChallenge, RefreshToken, Session, Token, TokenStore, WebauthnCeremony,
+    WebauthnChallenge,
+};
+use crate::{
+    util::{is_token, unix_now},
+    AuthError,
+};
+
+/// Refills a token bucket for the time since it was last used and takes a
+/// request from it, returning how many milliseconds until the next one if
+/// it is empty. Buckets left alone long enough to fill up are deleted.
+const RATE_LIMIT_SCRIPT: &str = r"
+local burst = tonumber(ARGV[1])
+local per_second = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or burst
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated_at) * per_second / 1000)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * 1000 / per_second)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / per_second))
+return wait
+";
+
+/// Keeps tokens in redis over a single multiplexed connection, which is
+/// cheap to clone and reconnects on its own
+#[derive(Clone)]
+pub struct RedisTokenStore {
+    conn: ConnectionManager,
+    prefix: String,
+    rate_limit_script: Script,
+}
+
+impl RedisTokenStore {
+    pub async fn new(redis_url: String) -> Result<Self, AuthError> {
+        let client = redis::Client::open(redis_url).map_err(AuthError::RedisError)?;
+        let conn = ConnectionManager::new(client)
+            .await
+            .map_err(AuthError::RedisError)?;
+        Ok(RedisTokenStore {
+            conn,
+            prefix: String::new(),
+            rate_limit_script: Script::new(RATE_LIMIT_SCRIPT),
+        })
+    }
+
+    /// Prefix every key with a namespace, so several apps can share a database
+    pub fn prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    fn connect(&self) -> ConnectionManager {
+        self.conn.clone()
+    }
+
+    /// Tokens get a namespace of their own, so no token can name another
+    /// kind of key
+    fn token_key(&self, token: &str) -> String {
+        format!("{}token:{}", self.prefix, token)
+    }
+
+    /// Key of the set holding every token issued to a user, so they can be
+    /// found without scanning the whole keyspace
+    fn user_tokens_key(&self, id: i32) -> String {
+        format!("{}user:{}:tokens", self.prefix, id)
+    }
+
+    fn denied_key(&self, jti: &str) -> String {
+        format!("{}denied:{}", self.prefix, jti)
+    }
+
+    /// Key tokens were kept at before they had a namespace
+    fn legacy_token_key(&self, token: &str) -> String {
+        format!("{}{}", self.prefix, token)
+    }
+
+    /// Refresh tokens are kept apart from access tokens, so they can not be
+    /// used as one
+    fn refresh_token_key(&self, token: &str) -> String {
+        format!("{}refresh:{}", self.prefix, token)
+    }
+
+    /// Refresh tokens are kept in the user's and session's token sets under
+    /// this name, which access tokens can not have as they have no colons
+    fn refresh_token_name(token: &str) -> String {
+        format!("refresh:{}", token)
+    }
+
+    /// Keys a token kept in a user's or session's token set can be at
+    fn member_keys(&self, member: &str) -> Vec<String> {
+        match member.strip_prefix("refresh:") {
+            Some(token) => vec![self.refresh_token_key(token)],
+            None => vec![self.token_key(member), self.legacy_token_key(member)],
+        }
+    }
+
+    /// Key of the hash holding a session's metadata
+    fn session_key(&self, id: &str) -> String {
+        format!("{}session:{}", self.prefix, id)
+    }
+
+    /// Key of the set holding every token issued in a session
+    fn session_tokens_key(&self, id: &str) -> String {
+        format!("{}session:{}:tokens", self.prefix, id)
+    }
+
+    /// Key of the hash holding a login waiting for a second factor
+    fn mfa_challenge_key(&self, challenge: &str) -> String {
+        format!("{}mfa:{}", self.prefix, challenge)
+    }
+
+    /// Key of the hash holding a challenge waiting to be signed by an
+    /// authenticator
+    fn webauthn_challenge_key(&self, challenge: &str) -> String {
+        format!("{}webauthn:{}", self.prefix, challenge)
+    }
+
+    /// Key of the hash counting failed logins for an email or an IP
+    fn login_failures_key(&self, key: &str) -> String {
+        format!("{}login_failures:{}", self.prefix, key)
+    }
+
+    /// Key of the hash holding a rate limit's token bucket
+    fn rate_limit_key(&self, key: &str) -> String {
+        format!("{}rate_limit:{}", self.prefix, key)
+    }
+
+    fn user_sessions_key(&self, user_id: i32) -> String {
+        format!("{}user:{}:sessions", self.prefix, user_id)
+    }
+
+    /// Tokens are hashes holding the user id, issue time and session
+    async fn read_token(
+        &self,
+        conn: &mut ConnectionManager,
+        key: &str,
+    ) -> Result<Option<Token>, AuthError> {
+        let (fields, ttl): (HashMap<String, String>, i64) = redis::pipe()
+            .hgetall(key)
+            .ttl(key)
+            .query_async(conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let Some(id) = fields.get("id").and_then(|x| x.parse().ok()) else {
+            return Ok(None);
+        };
+        Ok(Some(Token {
+            id,
+            issued_at: fields.get("issued_at").and_then(|x| x.parse().ok()),
+            // negative when the key has no expire time
+            expires_at: u64::try_from(ttl).ok().map(|x| unix_now() + x),
+            session: fields.get("session").cloned(),
+            session_expires_at: fields
+                .get("session_expires_at")
+                .and_then(|x| x.parse().ok()),
+        }))
+    }
+
+    /// Move a token kept where it was before tokens had a namespace into the
+    /// namespace. The oldest versions kept a plain string holding the user
+    /// id, later ones the same hash as now.
+    async fn migrate_legacy_token(
+        &self,
+        conn: &mut ConnectionManager,
+        token: &str,
+    ) -> Result<(), AuthError> {
+        let legacy = self.legacy_token_key(token);
+        let key = self.token_key(token);
+        let kind: String = redis::cmd("TYPE")
+            .arg(&legacy)
+            .query_async(conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        match kind.as_str() {
+            "hash" => conn
+                .rename_nx::<_, ()>(&legacy, &key)
+                .await
+                .map_err(AuthError::RedisError),
+            "string" => {
+                let (id, ttl): (Option<i32>, i64) = redis::pipe()
+                    .get(&legacy)
+                    .ttl(&legacy)
+                    .query_async(conn)
+                    .await
+                    .map_err(AuthError::RedisError)?;
+                let Some(id) = id else {
+                    return Ok(());
+                };
+                let mut pipe = redis::pipe();
+                pipe.atomic().hset(&key, "id", id).ignore();
+                // negative when the key has no expire time
+                if let Ok(x) = usize::try_from(ttl) {
+                    pipe.expire(&key, x).ignore();
+                }
+                pipe.del(&legacy)
+                    .ignore()
+                    .query_async(conn)
+                    .await
+                    .map_err(AuthError::RedisError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove tokens that have expired from a user's token set
+    async fn prune_user_tokens(
+        &self,
+        conn: &mut ConnectionManager,
+        id: i32,
+    ) -> Result<(), AuthError> {
+        let key = self.user_tokens_key(id);
+        let tokens: Vec<String> = conn.smembers(&key).await.map_err(AuthError::RedisError)?;
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for token in &tokens {
+            pipe.exists(self.member_keys(token));
+        }
+        let exists: Vec<bool> = pipe
+            .query_async(conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let expired: Vec<&String> = tokens
+            .iter()
+            .zip(exists)
+            .filter(|(_, exists)| !exists)
+            .map(|(token, _)| token)
+            .collect();
+        if !expired.is_empty() {
+            conn.srem::<_, _, ()>(&key, expired)
+                .await
+                .map_err(AuthError::RedisError)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TokenStore for RedisTokenStore {
+    async fn get_token(&self, token: String) -> Result<Option<Token>, AuthError> {
+        let mut conn = self.connect();
+        let key = self.token_key(&token);
+        if let Some(stored) = self.read_token(&mut conn, &key).await? {
+            return Ok(Some(stored));
+        }
+        // only real tokens are looked for outside the namespace, where any
+        // other string could name another kind of key
+        if !is_token(&token) {
+            return Ok(None);
+        }
+        self.migrate_legacy_token(&mut conn, &token).await?;
+        self.read_token(&mut conn, &key).await
+    }
+
+    async fn set_token(
+        &self,
+        token: String,
+        id: i32,
+        session: Option<String>,
+        session_expires_at: Option<u64>,
+        expire: Option<usize>,
+    ) -> Result<(), AuthError> {
+        let mut conn = self.connect();
+        // expired tokens linger in the set until the user logs in again
+        self.prune_user_tokens(&mut conn, id).await?;
+        let key = self.token_key(&token);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(
+                &key,
+                &[
+                    ("id", id.to_string()),
+                    ("issued_at", unix_now().to_string()),
+                ],
+            )
+            .ignore();
+        if let Some(x) = expire {
+            pipe.expire(&key, x).ignore();
+        }
+        if let Some(session) = session {
+            pipe.hset(&key, "session", &session).ignore();
+            pipe.sadd(self.session_tokens_key(&session), &token)
+                .ignore();
+        }
+        if let Some(x) = session_expires_at {
+            pipe.hset(&key, "session_expires_at", x).ignore();
+        }
+        pipe.sadd(self.user_tokens_key(id), &token).ignore();
+        pipe.query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn expire_token(&self, token: String, expire: usize) -> Result<(), AuthError> {
+        self.connect()
+            .expire::<_, ()>(self.token_key(&token), expire)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn delete_token(&self, token: String) -> Result<bool, AuthError> {
+        let mut conn = self.connect();
+        let key = self.token_key(&token);
+        let Some(Token { id, .. }) = self.read_token(&mut conn, &key).await? else {
+            return Ok(false);
+        };
+        let (deleted,): (i32,) = redis::pipe()
+            .atomic()
+            .del(&key)
+            .srem(self.user_tokens_key(id), &token)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        Ok(deleted > 0)
+    }
+
+    async fn delete_user_tokens(&self, id: i32) -> Result<(), AuthError> {
+        let mut conn = self.connect();
+        let key = self.user_tokens_key(id);
+        let sessions_key = self.user_sessions_key(id);
+        let (tokens, sessions): (Vec<String>, Vec<String>) = redis::pipe()
+            .smembers(&key)
+            .smembers(&sessions_key)
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let mut keys: Vec<String> = tokens.iter().flat_map(|x| self.member_keys(x)).collect();
+        for session in &sessions {
+            keys.push(self.session_key(session));
+            keys.push(self.session_tokens_key(session));
+        }
+        keys.push(key);
+        keys.push(sessions_key);
+        conn.del(keys).await.map_err(AuthError::RedisError)
+    }
+
+    async fn deny_token(&self, jti: String, expire: usize) -> Result<(), AuthError> {
+        self.connect()
+            .set_ex::<_, _, ()>(self.denied_key(&jti), true, expire)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn is_token_denied(&self, jti: String) -> Result<bool, AuthError> {
+        self.connect()
+            .exists(self.denied_key(&jti))
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn set_refresh_token(
+        &self,
+        token: String,
+        id: i32,
+        session: String,
+        session_expires_at: Option<u64>,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let mut conn = self.connect();
+        self.prune_user_tokens(&mut conn, id).await?;
+        let name = Self::refresh_token_name(&token);
+        let key = self.refresh_token_key(&token);
+        let session_tokens_key = self.session_tokens_key(&session);
+        let mut fields = vec![("id", id.to_string()), ("session", session)];
+        if let Some(x) = session_expires_at {
+            fields.push(("session_expires_at", x.to_string()));
+        }
+        redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &fields)
+            .ignore()
+            .expire(&key, expire)
+            .ignore()
+            .sadd(session_tokens_key, &name)
+            .ignore()
+            .sadd(self.user_tokens_key(id), &name)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn use_refresh_token(&self, token: String) -> Result<Option<RefreshToken>, AuthError> {
+        let mut conn = self.connect();
+        let key = self.refresh_token_key(&token);
+        let (uses, mut fields): (u64, HashMap<String, String>) = redis::pipe()
+            .atomic()
+            .hincr(&key, "uses", 1)
+            .hgetall(&key)
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let (Some(id), Some(session)) = (
+            fields.get("id").and_then(|x| x.parse().ok()),
+            fields.remove("session"),
+        ) else {
+            // counting the use created the key if the token did not exist
+            conn.del::<_, ()>(&key)
+                .await
+                .map_err(AuthError::RedisError)?;
+            return Ok(None);
+        };
+        Ok(Some(RefreshToken {
+            id,
+            session,
+            used: uses > 1,
+            session_expires_at: fields
+                .get("session_expires_at")
+                .and_then(|x| x.parse().ok()),
+        }))
+    }
+
+    async fn set_mfa_challenge(
+        &self,
+        challenge: String,
+        id: i32,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let key = self.mfa_challenge_key(&challenge);
+        redis::pipe()
+            .atomic()
+            .hset(&key, "id", id)
+            .ignore()
+            .expire(&key, expire)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn use_mfa_challenge(
+        &self,
+        challenge: String,
+    ) -> Result<Option<MfaChallenge>, AuthError> {
+        let mut conn = self.connect();
+        let key = self.mfa_challenge_key(&challenge);
+        let (attempts, id): (u32, Option<i32>) = redis::pipe()
+            .atomic()
+            .hincr(&key, "attempts", 1)
+            .hget(&key, "id")
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let Some(id) = id else {
+            // counting the attempt created the key if the challenge did not exist
+            conn.del::<_, ()>(&key)
+                .await
+                .map_err(AuthError::RedisError)?;
+            return Ok(None);
+        };
+        Ok(Some(MfaChallenge { id, attempts }))
+    }
+
+    async fn delete_mfa_challenge(&self, challenge: String) -> Result<(), AuthError> {
+        self.connect()
+            .del::<_, ()>(self.mfa_challenge_key(&challenge))
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn set_webauthn_challenge(
+        &self,
+        challenge: String,
+        state: WebauthnChallenge,
+        expire: usize,
+    ) -> Result<(), AuthError> {
+        let key = self.webauthn_challenge_key(&challenge);
+        let mut fields = vec![("ceremony", state.ceremony.as_str().to_string())];
+        if let Some(x) = state.user_id {
+            fields.push(("user_id", x.to_string()));
+        }
+        redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &fields)
+            .ignore()
+            .expire(&key, expire)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn take_webauthn_challenge(
+        &self,
+        challenge: String,
+    ) -> Result<Option<WebauthnChallenge>, AuthError> {
+        let key = self.webauthn_challenge_key(&challenge);
+        let (fields,): (HashMap<String, String>,) = redis::pipe()
+            .atomic()
+            .hgetall(&key)
+            .del(&key)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)?;
+        let ceremony = match fields.get("ceremony").map(String::as_str) {
+            Some("registration") => WebauthnCeremony::Registration,
+            Some("authentication") => WebauthnCeremony::Authentication,
+            _ => return Ok(None),
+        };
+        Ok(Some(WebauthnChallenge {
+            ceremony,
+            user_id: fields.get("user_id").and_then(|x| x.parse().ok()),
+        }))
+    }
+
+    async fn add_login_failure(&self, key: String, expire: usize) -> Result<u32, AuthError> {
+        let key = self.login_failures_key(&key);
+        let (count,): (u32,) = redis::pipe()
+            .atomic()
+            .hincr(&key, "count", 1)
+            .expire(&key, expire)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)?;
+        Ok(count)
+    }
+
+    async fn lock_login(&self, key: String, until: u64, expire: usize) -> Result<(), AuthError> {
+        let key = self.login_failures_key(&key);
+        redis::pipe()
+            .atomic()
+            .hset(&key, "locked_until", until)
+            .ignore()
+            .expire(&key, expire)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn get_login_failures(&self, key: String) -> Result<LoginFailures, AuthError> {
+        let (count, locked_until): (Option<u32>, Option<u64>) = self
+            .connect()
+            .hget(self.login_failures_key(&key), &["count", "locked_until"])
+            .await
+            .map_err(AuthError::RedisError)?;
+        Ok(LoginFailures {
+            count: count.unwrap_or_default(),
+            locked_until,
+        })
+    }
+
+    async fn clear_login_failures(&self, key: String) -> Result<(), AuthError> {
+        self.connect()
+            .del::<_, ()>(self.login_failures_key(&key))
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn take_rate_limit(
+        &self,
+        key: String,
+        burst: u32,
+        per_second: f64,
+    ) -> Result<Option<u64>, AuthError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or_default();
+        let wait: u64 = self
+            .rate_limit_script
+            .key(self.rate_limit_key(&key))
+            .arg(burst)
+            .arg(per_second)
+            .arg(now)
+            .invoke_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)?;
+        Ok(Some(wait).filter(|x| *x > 0))
+    }
+
+    async fn create_session(&self, session: Session) -> Result<(), AuthError> {
+        let mut fields = vec![
+            ("user_id", session.user_id.to_string()),
+            ("created_at", session.created_at.to_string()),
+            ("last_used_at", session.last_used_at.to_string()),
+        ];
+        for (name, value) in [
+            ("ip", session.ip),
+            ("user_agent", session.user_agent),
+            ("device", session.device),
+        ] {
+            if let Some(value) = value {
+                fields.push((name, value));
+            }
+        }
+        redis::pipe()
+            .atomic()
+            .hset_multiple(self.session_key(&session.id), &fields)
+            .ignore()
+            .sadd(self.user_sessions_key(session.user_id), &session.id)
+            .ignore()
+            .query_async(&mut self.connect())
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn touch_session(&self, id: String, expire: Option<usize>) -> Result<(), AuthError> {
+        let mut conn = self.connect();
+        let key = self.session_key(&id);
+        // setting a field would bring back a session that was deleted
+        let exists: bool = conn.exists(&key).await.map_err(AuthError::RedisError)?;
+        if !exists {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(&key, "last_used_at", unix_now())
+            .ignore();
+        if let Some(x) = expire {
+            pipe.expire(&key, x)
+                .ignore()
+                .expire(self.session_tokens_key(&id), x)
+                .ignore();
+        }
+        pipe.query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<Session>, AuthError> {
+        let mut conn = self.connect();
+        let key = self.user_sessions_key(user_id);
+        let ids: Vec<String> = conn.smembers(&key).await.map_err(AuthError::RedisError)?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(self.session_key(id));
+        }
+        let rows: Vec<HashMap<String, String>> = pipe
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let mut sessions = Vec::new();
+        let mut expired = Vec::new();
+        for (id, mut fields) in ids.into_iter().zip(rows) {
+            let number = |fields: &HashMap<String, String>, name| {
+                fields.get(name).and_then(|x| x.parse().ok())
+            };
+            match (
+                number(&fields, "created_at"),
+                number(&fields, "last_used_at"),
+            ) {
+                (Some(created_at), Some(last_used_at)) => sessions.push(Session {
+                    id,
+                    user_id,
+                    created_at,
+                    last_used_at,
+                    ip: fields.remove("ip"),
+                    user_agent: fields.remove("user_agent"),
+                    device: fields.remove("device"),
+                }),
+                _ => expired.push(id),
+            }
+        }
+        if !expired.is_empty() {
+            conn.srem::<_, _, ()>(&key, expired)
+                .await
+                .map_err(AuthError::RedisError)?;
+        }
+        Ok(sessions)
+    }
+
+    async fn delete_session(&self, id: String) -> Result<(), AuthError> {
+        let mut conn = self.connect();
+        let key = self.session_key(&id);
+        let tokens_key = self.session_tokens_key(&id);
+        let (user_id, tokens): (Option<i32>, Vec<String>) = redis::pipe()
+            .hget(&key, "user_id")
+            .smembers(&tokens_key)
+            .query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)?;
+        let mut keys: Vec<String> = tokens.iter().flat_map(|x| self.member_keys(x)).collect();
+        keys.push(key);
+        keys.push(tokens_key);
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(keys).ignore();
+        if let Some(user_id) = user_id {
+            pipe.srem(self.user_sessions_key(user_id), &id).ignore();
+        }
+        pipe.query_async(&mut conn)
+            .await
+            .map_err(AuthError::RedisError)
+    }
+}
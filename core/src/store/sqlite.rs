@@ -0,0 +1,1052 @@
+use async_trait::async_trait;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool, Sqlite,
+};
+use std::str::FromStr;
+
+use super::{EmailTokenKind, Passkey, Totp, User, UserStore};
+use crate::{util::unix_now, AuthError, SigningKey};
+
+/// Whether a query failed because a unique column already holds the value,
+/// SQLITE_CONSTRAINT_UNIQUE
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|x| x.code())
+        .is_some_and(|x| x == "2067")
+}
+
+/// Keeps users in a sqlite database, for small single binary deployments.
+///
+/// Unlike the postgres store this uses unchecked queries, since the offline
+/// query data can only describe one database.
+#[derive(Clone)]
+pub struct SqliteUserStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteUserStore {
+    pub async fn new(sqlite_url: String) -> Result<Self, AuthError> {
+        let options = SqliteConnectOptions::from_str(&sqlite_url)
+            .map_err(AuthError::SqliteError)?
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .map_err(AuthError::SqliteError)?;
+        init_db(&pool).await?;
+        Ok(SqliteUserStore { pool })
+    }
+}
+
+async fn init_db(pool: &Pool<Sqlite>) -> Result<(), AuthError> {
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "users" (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        email TEXT NOT NULL UNIQUE,
+        passwordhash TEXT NOT NULL,
+        salt TEXT NOT NULL
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    // sqlite has no ADD COLUMN IF NOT EXISTS, so check for the column first
+    let columns: Vec<(String,)> = sqlx::query_as(r#"SELECT name FROM pragma_table_info('users');"#)
+        .fetch_all(pool)
+        .await
+        .map_err(AuthError::SqliteError)?;
+    if !columns.iter().any(|(name,)| name == "is_admin") {
+        match sqlx::query(
+            r#"ALTER TABLE "users"
+            ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;"#,
+        )
+        .execute(pool)
+        .await
+        {
+            Ok(ok) => ok,
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+    }
+    // accounts created before emails were verified count as verified, new
+    // ones are inserted unverified
+    if !columns.iter().any(|(name,)| name == "email_verified") {
+        match sqlx::query(
+            r#"ALTER TABLE "users"
+            ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT TRUE;"#,
+        )
+        .execute(pool)
+        .await
+        {
+            Ok(ok) => ok,
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+    }
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "roles" (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "role_permissions" (
+        role_id INTEGER NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+        permission TEXT NOT NULL,
+        PRIMARY KEY (role_id, permission)
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "user_roles" (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        role_id INTEGER NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, role_id)
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "signing_keys" (
+        kid TEXT PRIMARY KEY,
+        algorithm TEXT NOT NULL,
+        private_key TEXT NOT NULL,
+        public_key TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        retired_at INTEGER,
+        expires_at INTEGER
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "email_tokens" (
+        token_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        kind TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "totp_secrets" (
+        user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+        secret TEXT NOT NULL,
+        confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+        last_step INTEGER NOT NULL DEFAULT 0
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "recovery_codes" (
+        code_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    match sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS "passkeys" (
+        credential_id TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        public_key TEXT NOT NULL,
+        algorithm INTEGER NOT NULL,
+        sign_count INTEGER NOT NULL,
+        name TEXT,
+        created_at INTEGER NOT NULL,
+        last_used_at INTEGER
+        );"#,
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::SqliteError(err)),
+    };
+    Ok(())
+}
+
+type PasskeyRow = (
+    String,
+    i32,
+    String,
+    i32,
+    i64,
+    Option<String>,
+    i64,
+    Option<i64>,
+);
+
+fn passkey_from_row(
+    (id, user_id, public_key, algorithm, sign_count, name, created_at, last_used_at): PasskeyRow,
+) -> Passkey {
+    Passkey {
+        id,
+        user_id,
+        public_key,
+        algorithm,
+        sign_count: sign_count as u32,
+        name,
+        created_at: created_at as u64,
+        last_used_at: last_used_at.map(|x| x as u64),
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteUserStore {
+    async fn create_user(&self, email: String, passwordhash: String) -> Result<(), AuthError> {
+        // the salt is stored inside of the hash
+        match sqlx::query(
+            r#"
+            INSERT INTO users ( email, passwordhash, salt, email_verified )
+            VALUES ( ?1, ?2, '', FALSE );
+            "#,
+        )
+        .bind(email)
+        .bind(passwordhash)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(ok) => ok,
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+        Ok(())
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<User, AuthError> {
+        match sqlx::query_as::<_, User>(
+            r#"
+            SELECT id, email, passwordhash, salt, is_admin, email_verified
+            FROM users
+            WHERE email = ?1;
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(AuthError::UserDoesNotExist),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn get_user_by_id(&self, id: i32) -> Result<User, AuthError> {
+        match sqlx::query_as::<_, User>(
+            r#"
+            SELECT id, email, passwordhash, salt, is_admin, email_verified
+            FROM users
+            WHERE id = ?1;
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(AuthError::UserDoesNotExist),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn update_user_by_email(
+        &self,
+        filter: String,
+        email: Option<String>,
+        passwordhash: Option<String>,
+    ) -> Result<(), AuthError> {
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        // a taken email leaves the password unchanged as well
+        let mut tx = self.pool.begin().await.map_err(AuthError::SqliteError)?;
+
+        if let Some(passwordhash) = passwordhash {
+            // clear any legacy salt, it is stored inside of the hash now
+            match sqlx::query(
+                r#"
+                UPDATE "users"
+                SET passwordhash = ?1,
+                    salt         = ''
+                WHERE email      = ?2;
+                "#,
+            )
+            .bind(passwordhash)
+            .bind(&filter)
+            .execute(&mut tx)
+            .await
+            {
+                Ok(ok) => ok,
+                Err(err) => return Err(AuthError::SqliteError(err)),
+            };
+        };
+
+        if let Some(email) = email {
+            match sqlx::query(
+                r#"
+                UPDATE "users"
+                SET email          = ?1,
+                    email_verified = email_verified AND email = ?1
+                WHERE email        = ?2;
+                "#,
+            )
+            .bind(email)
+            .bind(&filter)
+            .execute(&mut tx)
+            .await
+            {
+                Ok(ok) => ok,
+                Err(err) if is_unique_violation(&err) => return Err(AuthError::UserAlreadyExists),
+                Err(err) => return Err(AuthError::SqliteError(err)),
+            };
+        };
+
+        tx.commit().await.map_err(AuthError::SqliteError)
+    }
+
+    async fn delete_user_by_email(&self, filter: String) -> Result<(), AuthError> {
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        match sqlx::query(
+            r#"
+            DELETE FROM "users"
+            WHERE email = ?1;
+            "#,
+        )
+        .bind(filter)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn set_admin(&self, filter: String, is_admin: bool) -> Result<(), AuthError> {
+        // make sure user exists
+        self.get_user_by_email(filter.clone()).await?;
+
+        match sqlx::query(
+            r#"
+            UPDATE "users"
+            SET is_admin = ?1
+            WHERE email  = ?2;
+            "#,
+        )
+        .bind(is_admin)
+        .bind(filter)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, id: i32, email_verified: bool) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            UPDATE "users"
+            SET email_verified = ?1
+            WHERE id           = ?2;
+            "#,
+        )
+        .bind(email_verified)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::UserDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn add_email_token(
+        &self,
+        hash: String,
+        id: i32,
+        kind: EmailTokenKind,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE expires_at <= ?1;
+            "#,
+        )
+        .bind(unix_now() as i64)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+        match sqlx::query(
+            r#"
+            INSERT INTO email_tokens ( token_hash, user_id, kind, expires_at )
+            VALUES ( ?1, ?2, ?3, ?4 );
+            "#,
+        )
+        .bind(hash)
+        .bind(id)
+        .bind(kind.as_str())
+        .bind(expires_at as i64)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn get_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        match sqlx::query_as::<_, (i32,)>(
+            r#"
+            SELECT user_id
+            FROM "email_tokens"
+            WHERE token_hash = ?1
+              AND kind       = ?2
+              AND expires_at > ?3;
+            "#,
+        )
+        .bind(hash)
+        .bind(kind.as_str())
+        .bind(now as i64)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(row) => Ok(row.map(|(id,)| id)),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn take_email_token(
+        &self,
+        hash: String,
+        kind: EmailTokenKind,
+        now: u64,
+    ) -> Result<Option<i32>, AuthError> {
+        match sqlx::query_as::<_, (i32, i64)>(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE token_hash = ?1
+              AND kind       = ?2
+            RETURNING user_id, expires_at;
+            "#,
+        )
+        .bind(hash)
+        .bind(kind.as_str())
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some((id, expires_at))) if expires_at as u64 > now => Ok(Some(id)),
+            Ok(_) => Ok(None),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn delete_email_tokens(&self, id: i32, kind: EmailTokenKind) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            DELETE FROM "email_tokens"
+            WHERE user_id = ?1
+              AND kind    = ?2;
+            "#,
+        )
+        .bind(id)
+        .bind(kind.as_str())
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn set_totp(&self, id: i32, secret: String) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            INSERT INTO totp_secrets ( user_id, secret )
+            VALUES ( ?1, ?2 )
+            ON CONFLICT ( user_id ) DO UPDATE
+            SET secret    = ?2,
+                confirmed = FALSE,
+                last_step = 0;
+            "#,
+        )
+        .bind(id)
+        .bind(secret)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn get_totp(&self, id: i32) -> Result<Option<Totp>, AuthError> {
+        match sqlx::query_as::<_, (String, bool, i64)>(
+            r#"
+            SELECT secret, confirmed, last_step
+            FROM totp_secrets
+            WHERE user_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(row) => Ok(row.map(|(secret, confirmed, last_step)| Totp {
+                secret,
+                confirmed,
+                last_step: last_step as u64,
+            })),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn confirm_totp(&self, id: i32) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            UPDATE "totp_secrets"
+            SET confirmed = TRUE
+            WHERE user_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn use_totp_step(&self, id: i32, step: u64) -> Result<bool, AuthError> {
+        match sqlx::query(
+            r#"
+            UPDATE "totp_secrets"
+            SET last_step = ?1
+            WHERE user_id = ?2
+              AND last_step < ?1;
+            "#,
+        )
+        .bind(step as i64)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn delete_totp(&self, id: i32) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            DELETE FROM "totp_secrets"
+            WHERE user_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn set_recovery_codes(&self, id: i32, hashes: Vec<String>) -> Result<(), AuthError> {
+        let mut tx = self.pool.begin().await.map_err(AuthError::SqliteError)?;
+
+        match sqlx::query(
+            r#"
+            DELETE FROM "recovery_codes"
+            WHERE user_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        for hash in hashes {
+            match sqlx::query(
+                r#"
+                INSERT INTO recovery_codes ( code_hash, user_id )
+                VALUES ( ?1, ?2 );
+                "#,
+            )
+            .bind(hash)
+            .bind(id)
+            .execute(&mut tx)
+            .await
+            {
+                Ok(_) => {}
+                Err(err) => return Err(AuthError::SqliteError(err)),
+            };
+        }
+
+        tx.commit().await.map_err(AuthError::SqliteError)
+    }
+
+    async fn take_recovery_code(&self, id: i32, hash: String) -> Result<bool, AuthError> {
+        match sqlx::query(
+            r#"
+            DELETE FROM "recovery_codes"
+            WHERE user_id   = ?1
+              AND code_hash = ?2;
+            "#,
+        )
+        .bind(id)
+        .bind(hash)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn count_recovery_codes(&self, id: i32) -> Result<usize, AuthError> {
+        match sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COUNT(*)
+            FROM recovery_codes
+            WHERE user_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+        {
+            Ok(count) => Ok(count as usize),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn add_passkey(&self, passkey: Passkey) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            INSERT INTO passkeys ( credential_id, user_id, public_key, algorithm, sign_count, name, created_at, last_used_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 );
+            "#,
+        )
+        .bind(passkey.id)
+        .bind(passkey.user_id)
+        .bind(passkey.public_key)
+        .bind(passkey.algorithm)
+        .bind(passkey.sign_count as i64)
+        .bind(passkey.name)
+        .bind(passkey.created_at as i64)
+        .bind(passkey.last_used_at.map(|x| x as i64))
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn get_passkey(&self, id: String) -> Result<Option<Passkey>, AuthError> {
+        match sqlx::query_as::<_, PasskeyRow>(
+            r#"
+            SELECT credential_id, user_id, public_key, algorithm, sign_count, name, created_at, last_used_at
+            FROM passkeys
+            WHERE credential_id = ?1;
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(row) => Ok(row.map(passkey_from_row)),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn get_user_passkeys(&self, user_id: i32) -> Result<Vec<Passkey>, AuthError> {
+        match sqlx::query_as::<_, PasskeyRow>(
+            r#"
+            SELECT credential_id, user_id, public_key, algorithm, sign_count, name, created_at, last_used_at
+            FROM passkeys
+            WHERE user_id = ?1
+            ORDER BY created_at;
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => Ok(rows.into_iter().map(passkey_from_row).collect()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn update_passkey_use(
+        &self,
+        id: String,
+        sign_count: u32,
+        last_used_at: u64,
+    ) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            UPDATE "passkeys"
+            SET sign_count    = ?1,
+                last_used_at  = ?2
+            WHERE credential_id = ?3;
+            "#,
+        )
+        .bind(sign_count as i64)
+        .bind(last_used_at as i64)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn delete_passkey(&self, user_id: i32, id: String) -> Result<bool, AuthError> {
+        match sqlx::query(
+            r#"
+            DELETE FROM "passkeys"
+            WHERE user_id       = ?1
+              AND credential_id = ?2;
+            "#,
+        )
+        .bind(user_id)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn set_role(&self, role: String, permissions: Vec<String>) -> Result<(), AuthError> {
+        let mut tx = self.pool.begin().await.map_err(AuthError::SqliteError)?;
+
+        match sqlx::query(
+            r#"
+            INSERT INTO roles ( name )
+            VALUES ( ?1 )
+            ON CONFLICT ( name ) DO NOTHING;
+            "#,
+        )
+        .bind(&role)
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        let role_id: i32 = match sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM roles
+            WHERE name = ?1;
+            "#,
+        )
+        .bind(&role)
+        .fetch_one(&mut tx)
+        .await
+        {
+            Ok(id) => id,
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        match sqlx::query(
+            r#"
+            DELETE FROM "role_permissions"
+            WHERE role_id = ?1;
+            "#,
+        )
+        .bind(role_id)
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        for permission in permissions {
+            match sqlx::query(
+                r#"
+                INSERT INTO role_permissions ( role_id, permission )
+                VALUES ( ?1, ?2 )
+                ON CONFLICT DO NOTHING;
+                "#,
+            )
+            .bind(role_id)
+            .bind(permission)
+            .execute(&mut tx)
+            .await
+            {
+                Ok(_) => {}
+                Err(err) => return Err(AuthError::SqliteError(err)),
+            };
+        }
+
+        tx.commit().await.map_err(AuthError::SqliteError)
+    }
+
+    async fn delete_role(&self, role: String) -> Result<(), AuthError> {
+        // assignments and permissions are removed by the foreign keys
+        match sqlx::query(
+            r#"
+            DELETE FROM "roles"
+            WHERE name = ?1;
+            "#,
+        )
+        .bind(role)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::RoleDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+
+    async fn assign_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let role_id = self.get_role_id(role).await?;
+
+        match sqlx::query(
+            r#"
+            INSERT INTO user_roles ( user_id, role_id )
+            VALUES ( ?1, ?2 )
+            ON CONFLICT DO NOTHING;
+            "#,
+        )
+        .bind(id)
+        .bind(role_id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn revoke_role(&self, id: i32, role: String) -> Result<(), AuthError> {
+        let role_id = self.get_role_id(role).await?;
+
+        match sqlx::query(
+            r#"
+            DELETE FROM "user_roles"
+            WHERE user_id = ?1 AND role_id = ?2;
+            "#,
+        )
+        .bind(id)
+        .bind(role_id)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn get_roles(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        sqlx::query_scalar(
+            r#"
+            SELECT roles.name
+            FROM user_roles
+            JOIN roles ON roles.id = user_roles.role_id
+            WHERE user_roles.user_id = ?1
+            ORDER BY roles.name;
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(AuthError::SqliteError)
+    }
+
+    async fn get_permissions(&self, id: i32) -> Result<Vec<String>, AuthError> {
+        sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT role_permissions.permission
+            FROM user_roles
+            JOIN role_permissions ON role_permissions.role_id = user_roles.role_id
+            WHERE user_roles.user_id = ?1
+            ORDER BY role_permissions.permission;
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(AuthError::SqliteError)
+    }
+
+    async fn add_signing_key(&self, key: SigningKey) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            INSERT INTO signing_keys ( kid, algorithm, private_key, public_key, created_at, retired_at, expires_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+            ON CONFLICT ( kid ) DO NOTHING;
+            "#,
+        )
+        .bind(key.kid)
+        .bind(key.algorithm.to_string())
+        .bind(key.private_key)
+        .bind(key.public_key)
+        .bind(key.created_at as i64)
+        .bind(key.retired_at.map(|x| x as i64))
+        .bind(key.expires_at.map(|x| x as i64))
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+
+        Ok(())
+    }
+
+    async fn get_signing_keys(&self, now: u64) -> Result<Vec<SigningKey>, AuthError> {
+        type Row = (
+            String,
+            String,
+            String,
+            String,
+            i64,
+            Option<i64>,
+            Option<i64>,
+        );
+        let rows: Vec<Row> = match sqlx::query_as(
+            r#"
+            SELECT kid, algorithm, private_key, public_key, created_at, retired_at, expires_at
+            FROM signing_keys
+            WHERE expires_at IS NULL OR expires_at > ?1;
+            "#,
+        )
+        .bind(now as i64)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(err) => return Err(AuthError::SqliteError(err)),
+        };
+        rows.into_iter()
+            .map(
+                |(kid, algorithm, private_key, public_key, created_at, retired_at, expires_at)| {
+                    Ok(SigningKey {
+                        kid,
+                        algorithm: algorithm.parse().map_err(AuthError::JwtError)?,
+                        private_key,
+                        public_key,
+                        created_at: created_at as u64,
+                        retired_at: retired_at.map(|x| x as u64),
+                        expires_at: expires_at.map(|x| x as u64),
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn retire_signing_key(
+        &self,
+        kid: String,
+        retired_at: u64,
+        expires_at: u64,
+    ) -> Result<(), AuthError> {
+        match sqlx::query(
+            r#"
+            UPDATE "signing_keys"
+            SET retired_at = COALESCE(retired_at, ?1),
+                expires_at = COALESCE(expires_at, ?2)
+            WHERE kid      = ?3;
+            "#,
+        )
+        .bind(retired_at as i64)
+        .bind(expires_at as i64)
+        .bind(kid)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(AuthError::SigningKeyDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+}
+
+impl SqliteUserStore {
+    async fn get_role_id(&self, role: String) -> Result<i32, AuthError> {
+        match sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM roles
+            WHERE name = ?1;
+            "#,
+        )
+        .bind(role)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(id)) => Ok(id),
+            Ok(None) => Err(AuthError::RoleDoesNotExist),
+            Err(err) => Err(AuthError::SqliteError(err)),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Time based one time passwords as in RFC 6238, with the settings every
+//! common authenticator app understands: HMAC-SHA1, six digits and 30 second
+//! steps
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use sha1::Sha1;
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after the current one are accepted,
+/// to allow for clocks that drift
+const SKEW: u64 = 1;
+
+/// A random base32 secret of 160 bits, the size RFC 4226 recommends
+pub(crate) fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// `otpauth://` URI authenticator apps add a secret from, usually shown as
+/// a QR code
+pub(crate) fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC);
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, DIGITS, STEP
+    )
+}
+
+fn code_at(mut mac: Hmac<Sha1>, step: u64) -> u32 {
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+/// Check a code against a secret at unix time `now`, returning the time step
+/// it belongs to. Steps up to `last_step` are skipped, so a code that was
+/// accepted once is not accepted again.
+pub(crate) fn verify(secret: &str, code: &str, now: u64, last_step: u64) -> Option<u64> {
+    // apps often show codes split in two
+    let code: String = code.chars().filter(|x| !x.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|x| *x > last_step)
+        .find(|x| code_at(mac.clone(), *x) == code)
+}
+
+/// The code an authenticator app shows for a secret at unix time `now`
+#[cfg(test)]
+pub(crate) fn generate(secret: &str, now: u64) -> String {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let mac = Hmac::<Sha1>::new_from_slice(&key).unwrap();
+    format!("{:06}", code_at(mac, now / STEP))
+}
@@ -1,17 +1,16 @@
 use rand::{distributions::Alphanumeric, Rng};
-use redis::Commands;
 use sha2::{Digest, Sha256};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::error::AuthError;
+use super::{error::AuthError, store::TokenStore};
 
 pub(crate) async fn get_pool(postgres_url: String) -> Result<Pool<Postgres>, AuthError> {
     let pool = PgPoolOptions::new()
         .max_connections(5)
         .connect(&postgres_url)
         .await
-        .or_else(|x| Err(AuthError::PostgresError(x)))?;
-    init_db(&pool).await.unwrap();
+        .map_err(AuthError::PostgresError)?;
     Ok(pool)
 }
 
@@ -30,15 +29,154 @@ pub(crate) async fn init_db(pool: &Pool<Postgres>) -> Result<(), AuthError> {
         Ok(ok) => ok,
         Err(err) => return Err(AuthError::PostgresError(err)),
     };
+    match sqlx::query!(
+        r#"ALTER TABLE "users"
+        ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    // accounts created before emails were verified count as verified, new
+    // ones start out unverified
+    match sqlx::query!(
+        r#"ALTER TABLE "users"
+        ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT TRUE;"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"ALTER TABLE "users"
+        ALTER COLUMN email_verified SET DEFAULT FALSE;"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "roles" (
+        id INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "role_permissions" (
+        role_id INT NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+        permission TEXT NOT NULL,
+        PRIMARY KEY (role_id, permission)
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "user_roles" (
+        user_id INT NOT NULL,
+        role_id INT NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, role_id)
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "signing_keys" (
+        kid TEXT PRIMARY KEY,
+        algorithm TEXT NOT NULL,
+        private_key TEXT NOT NULL,
+        public_key TEXT NOT NULL,
+        created_at BIGINT NOT NULL,
+        retired_at BIGINT,
+        expires_at BIGINT
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "email_tokens" (
+        token_hash TEXT PRIMARY KEY,
+        user_id INT NOT NULL,
+        kind TEXT NOT NULL,
+        expires_at BIGINT NOT NULL
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "totp_secrets" (
+        user_id INT PRIMARY KEY,
+        secret TEXT NOT NULL,
+        confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+        last_step BIGINT NOT NULL DEFAULT 0
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "recovery_codes" (
+        code_hash TEXT PRIMARY KEY,
+        user_id INT NOT NULL
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
+    match sqlx::query!(
+        r#"CREATE TABLE IF NOT EXISTS "passkeys" (
+        credential_id TEXT PRIMARY KEY,
+        user_id INT NOT NULL,
+        public_key TEXT NOT NULL,
+        algorithm INT NOT NULL,
+        sign_count BIGINT NOT NULL,
+        name TEXT,
+        created_at BIGINT NOT NULL,
+        last_used_at BIGINT
+        );"#
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(ok) => ok,
+        Err(err) => return Err(AuthError::PostgresError(err)),
+    };
     Ok(())
 }
 
-pub(crate) fn hash(password: String, salt: String) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(password + &salt[..]);
-    format!("{:X}", hasher.finalize())
-}
-
 pub(crate) async fn connect(
     pool: &Pool<Postgres>,
 ) -> Result<sqlx::pool::PoolConnection<sqlx::Postgres>, AuthError> {
@@ -48,22 +186,49 @@ pub(crate) async fn connect(
     }
 }
 
-pub(crate) fn generate_token(redis: &mut redis::Client) -> Result<String, AuthError> {
-    let mut token: String = rand::thread_rng()
+/// Current unix time in seconds
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// Hex SHA-256 of a token, for storing tokens that are only ever looked up
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Whether a string could be a token from [`random_token`]. Anything else is
+/// refused before it reaches a token store, where it could name other keys.
+pub(crate) fn is_token(token: &str) -> bool {
+    token.len() == 32 && token.bytes().all(|x| x.is_ascii_alphanumeric())
+}
+
+pub(crate) fn random_token() -> String {
+    rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(32)
         .map(char::from)
+        .collect()
+}
+
+/// A code like `k3xq9-mv2ta` that is easy to read back and type, leaving
+/// out characters that are easily confused
+pub(crate) fn random_recovery_code() -> String {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..10)
+        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
         .collect();
-    while redis
-        .get(token.clone())
-        .or_else(|x| Err(AuthError::RedisError(x)))
-        .and_then(|x: Option<i32>| Ok(x.is_some()))?
-    {
-        token = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(32)
-            .map(char::from)
-            .collect();
+    code.insert(5, '-');
+    code
+}
+
+pub(crate) async fn generate_token(tokens: &dyn TokenStore) -> Result<String, AuthError> {
+    let mut token = random_token();
+    while tokens.get_token(token.clone()).await?.is_some() {
+        token = random_token();
     }
     Ok(token)
 }
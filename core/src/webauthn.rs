@@ -0,0 +1,804 @@
+//! Passkey registration and login following the WebAuthn spec. Registration
+//! asks authenticators for no attestation, so attestation statements are not
+//! checked, only the signatures of later logins are.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rsa::Rsa,
+    sign::Verifier,
+};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    store::{Passkey, User},
+    AuthError,
+};
+
+/// COSE algorithm ids of the keys that are accepted, in order of preference
+const ES256: i32 = -7;
+const EDDSA: i32 = -8;
+const RS256: i32 = -257;
+
+/// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// The site passkeys are registered for. Browsers only hand a passkey to
+/// pages on `origin`, and the origin's host has to be `rp_id` or one of its
+/// subdomains.
+#[derive(Clone, Debug)]
+pub struct WebauthnConfig {
+    /// Domain passkeys are bound to, like `example.com`
+    pub rp_id: String,
+    /// Name authenticators show for the site
+    pub rp_name: String,
+    /// Origin of the pages running the ceremonies, like `https://example.com`
+    pub origin: String,
+}
+
+impl WebauthnConfig {
+    pub fn new(rp_id: String, rp_name: String, origin: String) -> Self {
+        WebauthnConfig {
+            rp_id,
+            rp_name,
+            origin,
+        }
+    }
+}
+
+/// What `navigator.credentials.create()` resolves to, in the JSON form of
+/// `PublicKeyCredential.toJSON()` with binary fields base64url encoded
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// What `navigator.credentials.get()` resolves to, in the JSON form of
+/// `PublicKeyCredential.toJSON()` with binary fields base64url encoded
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoginCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(default)]
+    cross_origin: bool,
+}
+
+/// A credential that passed registration, not stored yet
+pub(crate) struct NewCredential {
+    pub id: String,
+    pub public_key: String,
+    pub algorithm: i32,
+    pub sign_count: u32,
+}
+
+fn invalid(reason: &str) -> AuthError {
+    AuthError::InvalidPasskey(reason.to_string())
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, AuthError> {
+    URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .map_err(|_| invalid("malformed base64"))
+}
+
+/// A random base64url challenge of 256 bits
+pub(crate) fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    URL_SAFE_NO_PAD.encode(challenge)
+}
+
+/// Binary ids are compared in canonical base64url, without padding
+pub(crate) fn canonical(id: &str) -> Result<String, AuthError> {
+    decode(id).map(|x| URL_SAFE_NO_PAD.encode(x))
+}
+
+/// The user handle authenticators keep with a passkey, so a login can be
+/// matched to the user without them typing their email
+pub(crate) fn user_handle(id: i32) -> String {
+    URL_SAFE_NO_PAD.encode(id.to_string())
+}
+
+fn descriptors(passkeys: &[Passkey]) -> Vec<serde_json::Value> {
+    passkeys
+        .iter()
+        .map(|x| json!({ "type": "public-key", "id": x.id }))
+        .collect()
+}
+
+/// Options for `navigator.credentials.create()`, leaving out passkeys the
+/// user already has on the same authenticator
+pub(crate) fn creation_options(
+    config: &WebauthnConfig,
+    user: &User,
+    challenge: &str,
+    timeout: usize,
+    existing: &[Passkey],
+) -> serde_json::Value {
+    let params: Vec<_> = [ES256, EDDSA, RS256]
+        .iter()
+        .map(|x| json!({ "type": "public-key", "alg": x }))
+        .collect();
+    json!({
+        "challenge": challenge,
+        "rp": { "id": config.rp_id, "name": config.rp_name },
+        "user": {
+            "id": user_handle(user.id),
+            "name": user.email,
+            "displayName": user.email,
+        },
+        "pubKeyCredParams": params,
+        "timeout": timeout * 1000,
+        "excludeCredentials": descriptors(existing),
+        "authenticatorSelection": {
+            "residentKey": "preferred",
+            "userVerification": "required",
+        },
+        "attestation": "none",
+    })
+}
+
+/// Options for `navigator.credentials.get()`. Without passkeys to allow,
+/// the authenticator offers every passkey it has for the site.
+pub(crate) fn request_options(
+    config: &WebauthnConfig,
+    challenge: &str,
+    timeout: usize,
+    allowed: &[Passkey],
+) -> serde_json::Value {
+    json!({
+        "challenge": challenge,
+        "rpId": config.rp_id,
+        "timeout": timeout * 1000,
+        "allowCredentials": descriptors(allowed),
+        "userVerification": "required",
+    })
+}
+
+/// Check the client data of a ceremony, returning its challenge
+fn verify_client_data(
+    config: &WebauthnConfig,
+    client_data_json: &[u8],
+    kind: &str,
+) -> Result<String, AuthError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| invalid("malformed client data"))?;
+    if client_data.kind != kind {
+        return Err(invalid("wrong ceremony type"));
+    }
+    if client_data.origin != config.origin || client_data.cross_origin {
+        return Err(invalid("wrong origin"));
+    }
+    Ok(client_data.challenge)
+}
+
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    /// Id and COSE public key of a newly created credential
+    credential: Option<(Vec<u8>, Value)>,
+}
+
+fn parse_authenticator_data(
+    config: &WebauthnConfig,
+    data: &[u8],
+) -> Result<AuthenticatorData, AuthError> {
+    if data.len() < 37 {
+        return Err(invalid("authenticator data is too short"));
+    }
+    if data[..32] != Sha256::digest(config.rp_id.as_bytes())[..] {
+        return Err(invalid("wrong relying party"));
+    }
+    let flags = data[32];
+    if flags & USER_PRESENT == 0 || flags & USER_VERIFIED == 0 {
+        return Err(invalid("user was not verified"));
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+    let credential = if flags & ATTESTED_CREDENTIAL_DATA != 0 {
+        // 16 byte authenticator model id, then the length of the credential id
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(invalid("credential data is too short"));
+        }
+        let length = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        if rest.len() < 18 + length {
+            return Err(invalid("credential data is too short"));
+        }
+        let mut key = &rest[18 + length..];
+        let key: Value =
+            ciborium::de::from_reader(&mut key).map_err(|_| invalid("malformed public key"))?;
+        Some((rest[18..18 + length].to_vec(), key))
+    } else {
+        None
+    };
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        credential,
+    })
+}
+
+/// Turn a COSE public key into a PEM one, along with its algorithm
+fn cose_to_pem(key: &Value) -> Result<(String, i32), AuthError> {
+    let entries = key
+        .as_map()
+        .ok_or_else(|| invalid("malformed public key"))?;
+    let get = |label: i128| {
+        entries
+            .iter()
+            .find(|(x, _)| x.as_integer().map(i128::from) == Some(label))
+            .map(|(_, x)| x)
+    };
+    let int = |label| get(label).and_then(Value::as_integer).map(i128::from);
+    let bytes = |label| {
+        get(label)
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| invalid("malformed public key"))
+    };
+    let openssl_error = |_| invalid("malformed public key");
+    // key type, algorithm and curve
+    let key: PKey<Public> = match (int(1), int(3), int(-1)) {
+        (Some(2), Some(-7), Some(1)) => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(openssl_error)?;
+            let x = BigNum::from_slice(bytes(-2)?).map_err(openssl_error)?;
+            let y = BigNum::from_slice(bytes(-3)?).map_err(openssl_error)?;
+            EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+                .and_then(PKey::from_ec_key)
+                .map_err(openssl_error)?
+        }
+        (Some(1), Some(-8), Some(6)) => {
+            PKey::public_key_from_raw_bytes(bytes(-2)?, Id::ED25519).map_err(openssl_error)?
+        }
+        (Some(3), Some(-257), _) => {
+            let n = BigNum::from_slice(bytes(-1)?).map_err(openssl_error)?;
+            let e = BigNum::from_slice(bytes(-2)?).map_err(openssl_error)?;
+            Rsa::from_public_components(n, e)
+                .and_then(PKey::from_rsa)
+                .map_err(openssl_error)?
+        }
+        _ => return Err(invalid("unsupported key type")),
+    };
+    let algorithm = int(3).unwrap_or_default() as i32;
+    let pem = key.public_key_to_pem().map_err(openssl_error)?;
+    Ok((String::from_utf8_lossy(&pem).into_owned(), algorithm))
+}
+
+/// Check the response to a registration, returning its challenge and the
+/// new credential. The challenge still has to be checked by the caller.
+pub(crate) fn verify_registration(
+    config: &WebauthnConfig,
+    credential: &RegistrationCredential,
+) -> Result<(String, NewCredential), AuthError> {
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    let challenge = verify_client_data(config, &client_data_json, "webauthn.create")?;
+    let attestation = decode(&credential.response.attestation_object)?;
+    let attestation: Value = ciborium::de::from_reader(&attestation[..])
+        .map_err(|_| invalid("malformed attestation object"))?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|x| x.iter().find(|(x, _)| x.as_text() == Some("authData")))
+        .and_then(|(_, x)| x.as_bytes())
+        .ok_or_else(|| invalid("malformed attestation object"))?;
+    let auth_data = parse_authenticator_data(config, auth_data)?;
+    let Some((id, key)) = auth_data.credential else {
+        return Err(invalid("no credential was created"));
+    };
+    let id = URL_SAFE_NO_PAD.encode(id);
+    if canonical(&credential.id)? != id {
+        return Err(invalid("credential id does not match"));
+    }
+    let (public_key, algorithm) = cose_to_pem(&key)?;
+    Ok((
+        challenge,
+        NewCredential {
+            id,
+            public_key,
+            algorithm,
+            sign_count: auth_data.sign_count,
+        },
+    ))
+}
+
+/// Check the response to a login against the passkey it claims to be from,
+/// returning its challenge and the authenticator's new sign count. The
+/// challenge still has to be checked by the caller.
+pub(crate) fn verify_assertion(
+    config: &WebauthnConfig,
+    credential: &LoginCredential,
+    passkey: &Passkey,
+) -> Result<(String, u32), AuthError> {
+    let response = &credential.response;
+    if let Some(handle) = &response.user_handle {
+        if canonical(handle)? != user_handle(passkey.user_id) {
+            return Err(invalid("user handle does not match"));
+        }
+    }
+    let client_data_json = decode(&response.client_data_json)?;
+    let challenge = verify_client_data(config, &client_data_json, "webauthn.get")?;
+    let auth_data = decode(&response.authenticator_data)?;
+    let parsed = parse_authenticator_data(config, &auth_data)?;
+    if parsed.flags & ATTESTED_CREDENTIAL_DATA != 0 {
+        return Err(invalid("unexpected credential data"));
+    }
+    let signature = decode(&response.signature)?;
+    let mut message = auth_data;
+    message.extend_from_slice(&Sha256::digest(&client_data_json));
+    let key = PKey::public_key_from_pem(passkey.public_key.as_bytes())
+        .map_err(|x| AuthError::WebauthnError(x.to_string()))?;
+    let verified = match passkey.algorithm {
+        EDDSA => Verifier::new_without_digest(&key)
+            .and_then(|mut x| x.verify_oneshot(&signature, &message)),
+        _ => Verifier::new(MessageDigest::sha256(), &key).and_then(|mut x| {
+            x.update(&message)?;
+            x.verify(&signature)
+        }),
+    };
+    // malformed signatures are errors rather than failed checks
+    if !verified.unwrap_or(false) {
+        return Err(invalid("wrong signature"));
+    }
+    // authenticators that count signatures count up, one that does not may
+    // have been cloned
+    if (parsed.sign_count != 0 || passkey.sign_count != 0)
+        && parsed.sign_count <= passkey.sign_count
+    {
+        return Err(invalid("sign count did not increase"));
+    }
+    Ok((challenge, parsed.sign_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::*, store::Passkey};
+    use openssl::{bn::BigNumContext, pkey::Private, sign::Signer};
+
+    const RP_ID: &str = "example.com";
+    const ORIGIN: &str = "https://example.com";
+
+    fn config() -> WebauthnConfig {
+        WebauthnConfig::new(RP_ID.to_string(), "Example".to_string(), ORIGIN.to_string())
+    }
+
+    /// A software authenticator holding one passkey, with knobs to make it
+    /// misbehave
+    struct Authenticator {
+        key: PKey<Private>,
+        algorithm: i32,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+        /// Whether the sign count goes up with every signature
+        counting: bool,
+        rp_id: String,
+        origin: String,
+        flags: u8,
+    }
+
+    impl Authenticator {
+        fn new(algorithm: i32) -> Self {
+            let key = match algorithm {
+                ES256 => {
+                    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+                    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+                }
+                EDDSA => PKey::generate_ed25519().unwrap(),
+                _ => PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            };
+            let mut credential_id = vec![0; 16];
+            rand::thread_rng().fill_bytes(&mut credential_id);
+            Authenticator {
+                key,
+                algorithm,
+                credential_id,
+                sign_count: 0,
+                counting: true,
+                rp_id: RP_ID.to_string(),
+                origin: ORIGIN.to_string(),
+                flags: USER_PRESENT | USER_VERIFIED,
+            }
+        }
+
+        fn id(&self) -> String {
+            URL_SAFE_NO_PAD.encode(&self.credential_id)
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let key = match self.algorithm {
+                ES256 => {
+                    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+                    let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+                    self.key
+                        .ec_key()
+                        .unwrap()
+                        .public_key()
+                        .affine_coordinates(
+                            &group,
+                            &mut x,
+                            &mut y,
+                            &mut BigNumContext::new().unwrap(),
+                        )
+                        .unwrap();
+                    vec![
+                        (1.into(), 2.into()),
+                        (3.into(), ES256.into()),
+                        ((-1).into(), 1.into()),
+                        ((-2).into(), Value::Bytes(x.to_vec_padded(32).unwrap())),
+                        ((-3).into(), Value::Bytes(y.to_vec_padded(32).unwrap())),
+                    ]
+                }
+                EDDSA => vec![
+                    (1.into(), 1.into()),
+                    (3.into(), EDDSA.into()),
+                    ((-1).into(), 6.into()),
+                    (
+                        (-2).into(),
+                        Value::Bytes(self.key.raw_public_key().unwrap()),
+                    ),
+                ],
+                _ => {
+                    let rsa = self.key.rsa().unwrap();
+                    vec![
+                        (1.into(), 3.into()),
+                        (3.into(), RS256.into()),
+                        ((-1).into(), Value::Bytes(rsa.n().to_vec())),
+                        ((-2).into(), Value::Bytes(rsa.e().to_vec())),
+                    ]
+                }
+            };
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&Value::Map(key), &mut bytes).unwrap();
+            bytes
+        }
+
+        fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+            json!({ "type": kind, "challenge": challenge, "origin": self.origin })
+                .to_string()
+                .into_bytes()
+        }
+
+        fn authenticator_data(&mut self, flags: u8) -> Vec<u8> {
+            if self.counting {
+                self.sign_count += 1;
+            }
+            let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            data
+        }
+
+        fn sign(&self, message: &[u8]) -> Vec<u8> {
+            match self.algorithm {
+                EDDSA => Signer::new_without_digest(&self.key)
+                    .unwrap()
+                    .sign_oneshot_to_vec(message)
+                    .unwrap(),
+                _ => {
+                    let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+                    signer.update(message).unwrap();
+                    signer.sign_to_vec().unwrap()
+                }
+            }
+        }
+
+        /// What `navigator.credentials.create()` resolves to
+        fn create(&mut self, challenge: &str) -> RegistrationCredential {
+            let client_data = self.client_data("webauthn.create", challenge);
+            let mut auth_data = self.authenticator_data(self.flags | ATTESTED_CREDENTIAL_DATA);
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            auth_data.extend_from_slice(&self.cose_key());
+            let attestation = Value::Map(vec![
+                ("fmt".into(), "none".into()),
+                ("attStmt".into(), Value::Map(Vec::new())),
+                ("authData".into(), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+            serde_json::from_value(json!({
+                "id": self.id(),
+                "response": {
+                    "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                    "attestationObject": URL_SAFE_NO_PAD.encode(attestation_object),
+                },
+            }))
+            .unwrap()
+        }
+
+        /// What `navigator.credentials.get()` resolves to
+        fn get(&mut self, challenge: &str, user_handle: Option<String>) -> LoginCredential {
+            let client_data = self.client_data("webauthn.get", challenge);
+            let auth_data = self.authenticator_data(self.flags);
+            let mut message = auth_data.clone();
+            message.extend_from_slice(&Sha256::digest(&client_data));
+            serde_json::from_value(json!({
+                "id": self.id(),
+                "response": {
+                    "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                    "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                    "signature": URL_SAFE_NO_PAD.encode(self.sign(&message)),
+                    "userHandle": user_handle,
+                },
+            }))
+            .unwrap()
+        }
+
+        /// Register with the authenticator, returning the passkey as stored
+        fn register(&mut self) -> Passkey {
+            let challenge = generate_challenge();
+            let (returned, new) = verify_registration(&config(), &self.create(&challenge)).unwrap();
+            assert_eq!(returned, challenge);
+            Passkey {
+                id: new.id,
+                user_id: 1,
+                public_key: new.public_key,
+                algorithm: new.algorithm,
+                sign_count: new.sign_count,
+                name: None,
+                created_at: 0,
+                last_used_at: None,
+            }
+        }
+    }
+
+    fn reason<T>(result: Result<T, AuthError>) -> String {
+        match result {
+            Err(AuthError::InvalidPasskey(x)) => x,
+            Err(x) => panic!("unexpected error {}", x),
+            Ok(_) => panic!("was accepted"),
+        }
+    }
+
+    #[test]
+    fn registers_and_verifies_every_algorithm() {
+        for algorithm in [ES256, EDDSA, RS256] {
+            let mut authenticator = Authenticator::new(algorithm);
+            let passkey = authenticator.register();
+            assert_eq!(passkey.id, authenticator.id());
+            assert_eq!(passkey.algorithm, algorithm);
+            assert_eq!(passkey.sign_count, 1);
+            let challenge = generate_challenge();
+            let credential = authenticator.get(&challenge, Some(user_handle(1)));
+            let (returned, sign_count) =
+                verify_assertion(&config(), &credential, &passkey).unwrap();
+            assert_eq!(returned, challenge);
+            assert_eq!(sign_count, 2);
+        }
+    }
+
+    #[test]
+    fn refuses_wrong_signatures() {
+        let mut authenticator = Authenticator::new(ES256);
+        let passkey = authenticator.register();
+        // signed by another key
+        let mut other = Authenticator::new(ES256);
+        other.credential_id = authenticator.credential_id.clone();
+        let credential = other.get(&generate_challenge(), None);
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "wrong signature"
+        );
+        // client data changed after signing
+        let mut credential = authenticator.get(&generate_challenge(), None);
+        credential.response.client_data_json = URL_SAFE_NO_PAD
+            .encode(authenticator.client_data("webauthn.get", &generate_challenge()));
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "wrong signature"
+        );
+        // not a signature at all
+        let mut credential = authenticator.get(&generate_challenge(), None);
+        credential.response.signature = URL_SAFE_NO_PAD.encode([1, 2, 3]);
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "wrong signature"
+        );
+    }
+
+    #[test]
+    fn checks_sign_counts() {
+        let mut authenticator = Authenticator::new(ES256);
+        let mut passkey = authenticator.register();
+        // a clone that fell behind
+        passkey.sign_count = 10;
+        let credential = authenticator.get(&generate_challenge(), None);
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "sign count did not increase"
+        );
+        // authenticators that do not count always report zero
+        let mut authenticator = Authenticator::new(EDDSA);
+        authenticator.counting = false;
+        let passkey = authenticator.register();
+        assert_eq!(passkey.sign_count, 0);
+        for _ in 0..2 {
+            let credential = authenticator.get(&generate_challenge(), None);
+            let (_, sign_count) = verify_assertion(&config(), &credential, &passkey).unwrap();
+            assert_eq!(sign_count, 0);
+        }
+    }
+
+    #[test]
+    fn checks_client_data() {
+        let mut authenticator = Authenticator::new(ES256);
+        authenticator.origin = "https://evil.example.com".to_string();
+        let credential = authenticator.create(&generate_challenge());
+        assert_eq!(
+            reason(verify_registration(&config(), &credential)),
+            "wrong origin"
+        );
+        let mut authenticator = Authenticator::new(ES256);
+        let passkey = authenticator.register();
+        // a login response passed off as a registration
+        let credential = authenticator.get(&generate_challenge(), None);
+        let mut registration = authenticator.create(&generate_challenge());
+        registration.response.client_data_json = credential.response.client_data_json;
+        assert_eq!(
+            reason(verify_registration(&config(), &registration)),
+            "wrong ceremony type"
+        );
+        let mut credential = authenticator.get(&generate_challenge(), None);
+        let client_data = json!({
+            "type": "webauthn.get",
+            "challenge": generate_challenge(),
+            "origin": ORIGIN,
+            "crossOrigin": true,
+        });
+        credential.response.client_data_json = URL_SAFE_NO_PAD.encode(client_data.to_string());
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "wrong origin"
+        );
+    }
+
+    #[test]
+    fn checks_authenticator_data() {
+        let mut authenticator = Authenticator::new(ES256);
+        authenticator.rp_id = "evil.com".to_string();
+        let credential = authenticator.create(&generate_challenge());
+        assert_eq!(
+            reason(verify_registration(&config(), &credential)),
+            "wrong relying party"
+        );
+        let mut authenticator = Authenticator::new(ES256);
+        let passkey = authenticator.register();
+        authenticator.flags = USER_PRESENT;
+        let credential = authenticator.get(&generate_challenge(), None);
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "user was not verified"
+        );
+        let mut credential = authenticator.get(&generate_challenge(), None);
+        credential.response.authenticator_data = URL_SAFE_NO_PAD.encode([0; 36]);
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "authenticator data is too short"
+        );
+    }
+
+    #[test]
+    fn checks_credential_ids_and_user_handles() {
+        let mut authenticator = Authenticator::new(ES256);
+        let mut credential = authenticator.create(&generate_challenge());
+        credential.id = URL_SAFE_NO_PAD.encode([0; 16]);
+        assert_eq!(
+            reason(verify_registration(&config(), &credential)),
+            "credential id does not match"
+        );
+        let passkey = authenticator.register();
+        let credential = authenticator.get(&generate_challenge(), Some(user_handle(2)));
+        assert_eq!(
+            reason(verify_assertion(&config(), &credential, &passkey)),
+            "user handle does not match"
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_cbor() {
+        let mut authenticator = Authenticator::new(ES256);
+        let mut credential = authenticator.create(&generate_challenge());
+        credential.response.attestation_object = URL_SAFE_NO_PAD.encode([0xa3, 0x63]);
+        assert_eq!(
+            reason(verify_registration(&config(), &credential)),
+            "malformed attestation object"
+        );
+        // a public key of a type that is not supported
+        let key = Value::Map(vec![(1.into(), 2.into()), (3.into(), (-35).into())]);
+        assert_eq!(reason(cose_to_pem(&key)), "unsupported key type");
+        // an EC key with a point that is not on the curve
+        let key = Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), ES256.into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), Value::Bytes(vec![1; 32])),
+            ((-3).into(), Value::Bytes(vec![1; 32])),
+        ]);
+        assert_eq!(reason(cose_to_pem(&key)), "malformed public key");
+        assert_eq!(reason(decode("not base64!")), "malformed base64");
+    }
+
+    #[tokio::test]
+    async fn registers_and_logs_in_through_auth() {
+        let auth = init_memory_auth().webauthn(config());
+        let (email, password) = ("passkey@example.com".to_string(), "passkey".to_string());
+        create_user(&auth, email.clone(), password.clone())
+            .await
+            .unwrap();
+        let token = match login(&auth, email.clone(), password, ClientInfo::default())
+            .await
+            .unwrap()
+        {
+            LoginResult::Tokens(x) => x.token,
+            LoginResult::MfaRequired { .. } => panic!("mfa is not enabled"),
+        };
+
+        // without a sign count only the challenge stops a replay
+        let mut authenticator = Authenticator::new(ES256);
+        authenticator.counting = false;
+        let options = start_passkey_registration(&auth, token.clone())
+            .await
+            .unwrap();
+        let handle = options["user"]["id"].as_str().unwrap().to_string();
+        let credential = authenticator.create(options["challenge"].as_str().unwrap());
+        finish_passkey_registration(&auth, token.clone(), credential.clone(), None)
+            .await
+            .unwrap();
+        // each challenge is only good for one registration
+        let result = finish_passkey_registration(&auth, token.clone(), credential, None).await;
+        assert_eq!(reason(result), "unknown challenge");
+        assert_eq!(list_passkeys(&auth, token).await.unwrap().len(), 1);
+
+        let options = start_passkey_login(&auth, None).await.unwrap();
+        let credential = authenticator.get(options["challenge"].as_str().unwrap(), Some(handle));
+        let tokens = finish_passkey_login(&auth, credential.clone(), ClientInfo::default())
+            .await
+            .unwrap();
+        let info = verify_token(&auth, tokens.token).await.unwrap().unwrap();
+        assert_eq!(info.email, email);
+        let result = finish_passkey_login(&auth, credential, ClientInfo::default()).await;
+        assert_eq!(reason(result), "unknown challenge");
+
+        // challenges are not made up by the client
+        let credential = authenticator.get(&generate_challenge(), None);
+        let result = finish_passkey_login(&auth, credential, ClientInfo::default()).await;
+        assert_eq!(reason(result), "unknown challenge");
+    }
+}
@@ -0,0 +1,95 @@
+//! Load test for a running passtoken server.
+//!
+//! Registers a throwaway user, then keeps `connections` keep-alive
+//! connections busy with either `/login` (password hashing, CPU bound) or
+//! `/token` (storage bound) requests for `seconds` seconds and prints the
+//! throughput. Run the server with different `WORKERS` values to compare:
+//!
+//! ```sh
+//! STORAGE=memory WORKERS=1 cargo run --release &
+//! cargo run --release --example load_test -- 127.0.0.1:8080 login 32 10
+//! ```
+
+use common::Connection;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+mod common;
+
+fn main() {
+    // built by hand as the `core` dependency shadows the one `#[tokio::main]` expands to
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run());
+}
+
+async fn run() {
+    let args: Vec<String> = env::args().collect();
+    let address = args.get(1).cloned().unwrap_or("127.0.0.1:8080".to_string());
+    let mode = args.get(2).cloned().unwrap_or("login".to_string());
+    let connections: usize = args.get(3).and_then(|x| x.parse().ok()).unwrap_or(32);
+    let seconds: u64 = args.get(4).and_then(|x| x.parse().ok()).unwrap_or(10);
+
+    // Set up a user and a token to work with
+    let email = format!("load-test-{}@example.com", std::process::id());
+    let credentials = serde_json::json!({ "email": email, "password": "load-test" }).to_string();
+    let mut setup = Connection::open(&address)
+        .await
+        .expect("could not connect to server");
+    setup.request("POST", "/user", &credentials).await.unwrap();
+    let (status, body) = setup.request("POST", "/login", &credentials).await.unwrap();
+    assert_eq!(status, 200, "could not log in: {}", body);
+    let token = serde_json::from_str::<serde_json::Value>(&body).unwrap()["token"].clone();
+
+    let (method, path, body) = match mode.as_str() {
+        "login" => ("POST", "/login", credentials),
+        "token" => (
+            "GET",
+            "/token",
+            serde_json::json!({ "token": token }).to_string(),
+        ),
+        _ => panic!("mode must be login or token"),
+    };
+
+    let ok = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let tasks: Vec<_> = (0..connections)
+        .map(|_| {
+            let (address, body) = (address.clone(), body.clone());
+            let (ok, failed) = (Arc::clone(&ok), Arc::clone(&failed));
+            tokio::spawn(async move {
+                let mut conn = Connection::open(&address).await.unwrap();
+                while Instant::now() < deadline {
+                    match conn.request(method, path, &body).await {
+                        Ok((200, _)) => ok.fetch_add(1, Ordering::Relaxed),
+                        _ => failed.fetch_add(1, Ordering::Relaxed),
+                    };
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let ok = ok.load(Ordering::Relaxed);
+    println!(
+        "{} {} requests over {} connections in {}s: {} ok, {} failed, {:.1} req/s",
+        method,
+        path,
+        connections,
+        seconds,
+        ok,
+        failed.load(Ordering::Relaxed),
+        ok as f64 / seconds as f64
+    );
+}
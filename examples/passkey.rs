@@ -0,0 +1,217 @@
+//! Software authenticator for trying passkeys against a running passtoken
+//! server.
+//!
+//! Registers a throwaway user, adds a P-256 passkey to it the way a browser
+//! would, logs in with the passkey and checks that the login can not be
+//! replayed. The server has to accept the given origin:
+//!
+//! ```sh
+//! WEBAUTHN_RP_ID=localhost WEBAUTHN_ORIGIN=http://localhost:8080 cargo run &
+//! cargo run --example passkey -- 127.0.0.1:8080 http://localhost:8080
+//! ```
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use common::Connection;
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+
+mod common;
+
+/// Authenticator data flags for a present and verified user, and for a
+/// newly created credential
+const USER_PRESENT_VERIFIED: u8 = 0x05;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+struct Authenticator {
+    key: PKey<Private>,
+    credential_id: Vec<u8>,
+    origin: String,
+    sign_count: u32,
+}
+
+impl Authenticator {
+    fn new(origin: String) -> Self {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut credential_id = vec![0; 16];
+        openssl::rand::rand_bytes(&mut credential_id).unwrap();
+        Authenticator {
+            key: PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+            credential_id,
+            origin,
+            sign_count: 0,
+        }
+    }
+
+    fn id(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.credential_id)
+    }
+
+    fn client_data(&self, kind: &str, challenge: &serde_json::Value) -> Vec<u8> {
+        json!({ "type": kind, "challenge": challenge, "origin": self.origin })
+            .to_string()
+            .into_bytes()
+    }
+
+    /// The public key as a COSE key
+    fn cose_key(&self) -> Vec<u8> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        self.key
+            .ec_key()
+            .unwrap()
+            .public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let key = Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), (-7).into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), Value::Bytes(x.to_vec_padded(32).unwrap())),
+            ((-3).into(), Value::Bytes(y.to_vec_padded(32).unwrap())),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn authenticator_data(&mut self, rp_id: &str, flags: u8) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        self.sign_count += 1;
+        data
+    }
+
+    /// What `navigator.credentials.create()` resolves to
+    fn create(&mut self, options: &serde_json::Value) -> serde_json::Value {
+        let rp_id = options["rp"]["id"].as_str().unwrap();
+        let client_data = self.client_data("webauthn.create", &options["challenge"]);
+        let mut auth_data =
+            self.authenticator_data(rp_id, USER_PRESENT_VERIFIED | ATTESTED_CREDENTIAL_DATA);
+        // no authenticator model id
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&self.cose_key());
+        let attestation = Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), Value::Map(Vec::new())),
+            ("authData".into(), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+        json!({
+            "id": self.id(),
+            "rawId": self.id(),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation_object),
+            },
+        })
+    }
+
+    /// What `navigator.credentials.get()` resolves to
+    fn get(&mut self, options: &serde_json::Value, user_handle: &str) -> serde_json::Value {
+        let rp_id = options["rpId"].as_str().unwrap();
+        let client_data = self.client_data("webauthn.get", &options["challenge"]);
+        let auth_data = self.authenticator_data(rp_id, USER_PRESENT_VERIFIED);
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+        signer.update(&auth_data).unwrap();
+        signer.update(&Sha256::digest(&client_data)).unwrap();
+        json!({
+            "id": self.id(),
+            "rawId": self.id(),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()),
+                "userHandle": user_handle,
+            },
+        })
+    }
+}
+
+fn main() {
+    // built by hand as the `core` dependency shadows the one `#[tokio::main]` expands to
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run());
+}
+
+/// Send a request, panicking unless it succeeds
+async fn expect_ok(
+    conn: &mut Connection,
+    method: &str,
+    path: &str,
+    body: serde_json::Value,
+) -> serde_json::Value {
+    let (status, body) = conn.request(method, path, &body.to_string()).await.unwrap();
+    assert_eq!(status, 200, "{} {} failed: {}", method, path, body);
+    serde_json::from_str(&body).unwrap_or_default()
+}
+
+async fn run() {
+    let args: Vec<String> = env::args().collect();
+    let address = args.get(1).cloned().unwrap_or("127.0.0.1:8080".to_string());
+    let origin = args
+        .get(2)
+        .cloned()
+        .unwrap_or("http://localhost:8080".to_string());
+    let mut conn = Connection::open(&address)
+        .await
+        .expect("could not connect to server");
+    let mut authenticator = Authenticator::new(origin);
+
+    // Set up a user with a password to register the passkey with
+    let email = format!("passkey-{}@example.com", std::process::id());
+    let credentials = json!({ "email": email, "password": "passkey" });
+    expect_ok(&mut conn, "POST", "/user", credentials.clone()).await;
+    let login = expect_ok(&mut conn, "POST", "/login", credentials).await;
+    let token = &login["token"];
+
+    let options = json!({ "token": token });
+    let options = expect_ok(&mut conn, "POST", "/user/passkeys/options", options).await;
+    let user_handle = options["user"]["id"].as_str().unwrap().to_string();
+    let credential = authenticator.create(&options);
+    let passkey = json!({ "token": token, "credential": credential, "name": "software" });
+    let passkey = expect_ok(&mut conn, "POST", "/user/passkeys", passkey).await;
+    println!("registered passkey {}", passkey["id"]);
+
+    // Log in without an email, the way a discoverable passkey would
+    let options = expect_ok(&mut conn, "POST", "/login/passkey/options", json!({})).await;
+    let credential = authenticator.get(&options, &user_handle);
+    let body = json!({ "credential": credential, "device": "software authenticator" });
+    let login = expect_ok(&mut conn, "POST", "/login/passkey", body.clone()).await;
+    let info = expect_ok(
+        &mut conn,
+        "GET",
+        "/token",
+        json!({ "token": login["token"] }),
+    )
+    .await;
+    assert_eq!(info["email"], email.as_str());
+    println!("logged in as {}", info["email"]);
+
+    let (status, _) = conn
+        .request("POST", "/login/passkey", &body.to_string())
+        .await
+        .unwrap();
+    assert_eq!(status, 401, "a replayed login was accepted");
+    println!("replayed login refused");
+
+    expect_ok(&mut conn, "DELETE", "/user", json!({ "token": token })).await;
+}
@@ -0,0 +1,375 @@
+use actix_web::{
+    error::JsonPayloadError,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    HttpRequest, HttpResponse, ResponseError,
+};
+use core::{
+    AuthError, LoginCredential, LoginTokens, Passkey, RegistrationCredential, Session,
+    TotpEnrollment, UserInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Request bodies
+
+/// Any request body, read only for the token it may carry
+#[derive(Deserialize)]
+pub(crate) struct AnyTokenRequest {
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginRequest {
+    pub email: String,
+    pub password: String,
+    /// Name of the device logging in, shown when listing sessions
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginMfaRequest {
+    pub challenge: String,
+    pub code: String,
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PasskeyOptionsRequest {
+    /// Only offer the passkeys of this user
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PasskeyLoginRequest {
+    pub credential: LoginCredential,
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TokenRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RegisterRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CompletePasswordResetRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TotpCodeRequest {
+    pub token: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RegisterPasskeyRequest {
+    pub token: String,
+    pub credential: RegistrationCredential,
+    /// Shown when listing passkeys
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateUserRequest {
+    pub token: String,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub logout: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdminUpdateUserRequest {
+    pub token: String,
+    pub filter: String,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub logout: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdminDeleteUserRequest {
+    pub token: String,
+    pub filter: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UnlockUserRequest {
+    pub token: String,
+    pub filter: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UnlockIpRequest {
+    pub token: String,
+    pub ip: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetAdminRequest {
+    pub token: String,
+    pub filter: String,
+    pub is_admin: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateRoleRequest {
+    pub token: String,
+    pub role: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteRoleRequest {
+    pub token: String,
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserRoleRequest {
+    pub token: String,
+    pub filter: String,
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PermissionRequest {
+    pub token: String,
+    pub permission: String,
+}
+
+// Response bodies
+
+#[derive(Serialize)]
+pub(crate) struct LoginResponse {
+    pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+impl From<LoginTokens> for LoginResponse {
+    fn from(tokens: LoginTokens) -> Self {
+        LoginResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+        }
+    }
+}
+
+/// Sent instead of tokens when the user has to give a second factor
+#[derive(Serialize)]
+pub(crate) struct MfaRequiredResponse {
+    pub mfa_required: bool,
+    pub challenge: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TotpEnrollmentResponse {
+    pub secret: String,
+    pub uri: String,
+}
+
+impl From<TotpEnrollment> for TotpEnrollmentResponse {
+    fn from(enrollment: TotpEnrollment) -> Self {
+        TotpEnrollmentResponse {
+            secret: enrollment.secret,
+            uri: enrollment.uri,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RecoveryCodeCountResponse {
+    pub remaining: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TokenResponse {
+    pub id: i32,
+    pub email: String,
+    pub email_verified: bool,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    pub session: Option<String>,
+    pub issued_at: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+impl From<UserInfo> for TokenResponse {
+    fn from(info: UserInfo) -> Self {
+        TokenResponse {
+            id: info.id,
+            email: info.email,
+            email_verified: info.email_verified,
+            roles: info.roles,
+            permissions: info.permissions,
+            session: info.session,
+            issued_at: info.issued_at,
+            expires_at: info.expires_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct SessionResponse {
+    pub id: String,
+    pub created_at: u64,
+    pub last_used_at: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub device: Option<String>,
+}
+
+impl From<Session> for SessionResponse {
+    fn from(session: Session) -> Self {
+        SessionResponse {
+            id: session.id,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            ip: session.ip,
+            user_agent: session.user_agent,
+            device: session.device,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct PasskeyResponse {
+    pub id: String,
+    pub name: Option<String>,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+impl From<Passkey> for PasskeyResponse {
+    fn from(passkey: Passkey) -> Self {
+        PasskeyResponse {
+            id: passkey.id,
+            name: passkey.name,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct PermissionResponse {
+    pub allowed: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+fn json_error(status: StatusCode, code: &'static str, message: String) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse {
+        error: ErrorBody { code, message },
+    })
+}
+
+/// Wraps auth errors so handlers can return them with `?` and have them
+/// turned into a JSON error response with a matching status code
+#[derive(Debug)]
+pub(crate) struct ApiError(pub AuthError);
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        ApiError(err)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self.0 {
+            AuthError::IncorrectUsernameOrPassword
+            | AuthError::IncorrectMfaCode
+            | AuthError::InvalidPasskey(_)
+            | AuthError::InvalidToken
+            | AuthError::TokenDoesNotExist => StatusCode::UNAUTHORIZED,
+            AuthError::PermissionDenied | AuthError::EmailNotVerified => StatusCode::FORBIDDEN,
+            AuthError::UserDoesNotExist
+            | AuthError::RoleDoesNotExist
+            | AuthError::SessionDoesNotExist
+            | AuthError::SigningKeyDoesNotExist
+            | AuthError::TotpNotEnabled
+            | AuthError::PasskeyDoesNotExist => StatusCode::NOT_FOUND,
+            AuthError::UserAlreadyExists | AuthError::TotpAlreadyEnabled => StatusCode::CONFLICT,
+            AuthError::AccountLocked(_)
+            | AuthError::TooManyAttempts(_)
+            | AuthError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::PostgresError(_) | AuthError::RedisError(_) | AuthError::SqliteError(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AuthError::UnableToAquireTokenListLock
+            | AuthError::PasswordHashError(_)
+            | AuthError::JwtError(_)
+            | AuthError::WebauthnError(_)
+            | AuthError::MailError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = json_error(
+            self.status_code(),
+            self.0.code(),
+            self.0.to_error_message().to_string(),
+        );
+        if let Some(x) = self.0.retry_after() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(x));
+        }
+        response
+    }
+}
+
+/// Report malformed or incomplete request bodies in the same shape as other errors
+pub(crate) fn json_payload_error(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    let response = json_error(StatusCode::BAD_REQUEST, "invalid_request", err.to_string());
+    actix_web::error::InternalError::from_response(err, response).into()
+}